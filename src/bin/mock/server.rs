@@ -67,11 +67,23 @@ pub(crate) async fn run(args: ServerArgs) -> Result<(), Error> {
     peers.register(&modex.addr()).unwrap();
 
     let server_dir = tmpdir.join("server");
-    let (s, e) = pmix::server::Server::init(&server_dir, &peers.hostname().unwrap()).unwrap();
+    let (s, e) = pmix::server::Server::init(&server_dir, &peers.hostname().unwrap(), None).unwrap();
 
     let hostnames = peers.hostnames().collect::<Vec<_>>();
     let namespace = &CString::new(namespace).unwrap();
-    let n = pmix::server::Namespace::register(&s, namespace, &hostnames, nprocs)?;
+    let apps = [pmix::server::App {
+        size: nnodes * nprocs as u32,
+        argv: None,
+    }];
+    let n = pmix::server::Namespace::register(
+        &s,
+        namespace,
+        namespace,
+        &hostnames,
+        nprocs,
+        &apps,
+        nnodes * nprocs as u32,
+    )?;
     let clients = peers
         .local_ranks()
         .map(|i| pmix::server::Client::register(&n, i))
@@ -0,0 +1,38 @@
+//! `pmi-k8s exec` as a standalone binary. It doesn't link the PMIx library, so
+//! it can be copied into application images that don't have it.
+
+use std::process;
+
+use anyhow::Error;
+use clap::Parser;
+use tracing_subscriber::{EnvFilter, filter::LevelFilter};
+
+#[allow(dead_code, reason = "only decoding is used here")]
+#[path = "../envfile.rs"]
+mod envfile;
+#[allow(dead_code, reason = "publishing is only used by the sidecar")]
+#[path = "../exec.rs"]
+mod exec;
+
+/// Wait for a `pmi-k8s` sidecar to write environment files to `--env-dir`,
+/// then run a command once for each of them.
+#[derive(Parser, Debug)]
+struct Cli {
+    #[command(flatten)]
+    exec: exec::Args,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    let args = Cli::parse();
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+    let (command, argv) = args.exec.command.split_first().expect("required by clap");
+    let code = exec::run(&args.exec.env_dir, command, argv).await?;
+    process::exit(code);
+}
@@ -0,0 +1,413 @@
+//! Layered configuration of the launcher. Each option may be set, from highest
+//! to lowest precedence, on the command line, by a `PMI_K8S_*` environment
+//! variable, or in a TOML or YAML file given by `--config` (e.g. mounted from a
+//! ConfigMap), before falling back to its default.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, parser::ValueSource};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::Cli;
+
+/// Prefix of the environment variable for each option, e.g. `--env-dir` is
+/// set by `PMI_K8S_ENV_DIR`.
+pub const ENV_PREFIX: &str = "PMI_K8S_";
+
+/// Options that can't be set in the config file.
+const NOT_IN_FILE: [&str; 2] = ["config", "print-config"];
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable to read {0}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("invalid TOML in {0}")]
+    Toml(PathBuf, #[source] toml::de::Error),
+    #[error("invalid YAML in {0}")]
+    Yaml(PathBuf, #[source] serde_yaml::Error),
+    #[error("unknown config file format (expected .toml, .yaml or .yml): {0}")]
+    Format(PathBuf),
+    #[error("unknown option {key:?} in {path}")]
+    Unknown { path: PathBuf, key: String },
+    #[error("invalid value for {key} ({origin})")]
+    InvalidValue { key: String, origin: Source },
+    #[error(transparent)]
+    Cli(#[from] clap::Error),
+}
+
+/// Where the effective value of an option came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env(String),
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::File(path) => write!(f, "from file {}", path.display()),
+            Self::Env(var) => write!(f, "from env {}", var),
+            Self::CommandLine => write!(f, "from command line"),
+        }
+    }
+}
+
+/// A value in the config file.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn scalar(&self) -> Option<String> {
+        match self {
+            Self::Bool(b) => Some(b.to_string()),
+            Self::Integer(i) => Some(i.to_string()),
+            Self::Float(x) => Some(x.to_string()),
+            Self::String(s) => Some(s.clone()),
+            Self::List(_) => None,
+        }
+    }
+}
+
+/// The effective value of one option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    /// The option's long name, e.g. `env-dir`.
+    pub name: String,
+    pub values: Vec<String>,
+    pub source: Source,
+    flag: bool,
+    list: bool,
+}
+
+/// The parsed command line, with options from the environment and config file
+/// filled in.
+#[derive(Debug)]
+pub struct Config {
+    pub cli: Cli,
+    /// Every option that has a value, in the order of `--help`.
+    pub settings: Vec<Setting>,
+}
+
+/// The environment variable that sets the option `long`.
+pub fn env_var(long: &str) -> String {
+    format!("{}{}", ENV_PREFIX, long.replace('-', "_").to_uppercase())
+}
+
+/// Load a config file into option values, keyed by long name. Keys may be
+/// written with `_` or `-`.
+fn load(path: &Path) -> Result<BTreeMap<String, Value>, Error> {
+    let contents = fs::read_to_string(path).map_err(|err| Error::Read(path.to_owned(), err))?;
+    let table: BTreeMap<String, Value> = match path.extension().and_then(OsStr::to_str) {
+        Some("toml") => {
+            toml::from_str(&contents).map_err(|err| Error::Toml(path.to_owned(), err))?
+        }
+        Some("yaml" | "yml") => {
+            serde_yaml::from_str(&contents).map_err(|err| Error::Yaml(path.to_owned(), err))?
+        }
+        _ => return Err(Error::Format(path.to_owned())),
+    };
+    Ok(table
+        .into_iter()
+        .map(|(key, value)| (key.replace('_', "-"), value))
+        .collect())
+}
+
+fn push_arg(args: &mut Vec<OsString>, long: &str, value: Option<&OsStr>) {
+    let mut arg = OsString::from(format!("--{}", long));
+    if let Some(value) = value {
+        arg.push("=");
+        arg.push(value);
+    }
+    args.push(arg);
+}
+
+impl Config {
+    /// Parse the process's arguments and environment, exiting on invalid
+    /// arguments (or `--help`) as [`Parser::parse`] does.
+    pub fn parse() -> Result<Self, Error> {
+        match Self::try_parse_from(std::env::args_os(), |var| std::env::var_os(var)) {
+            Err(Error::Cli(err)) => err.exit(),
+            result => result,
+        }
+    }
+
+    /// Parse `args`, looking up environment variables with `vars`.
+    pub fn try_parse_from<I, T>(
+        args: I,
+        vars: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let args = args.into_iter().map(Into::into).collect::<Vec<_>>();
+        let command = Cli::command();
+
+        // Subcommands have their own options, which aren't layered
+        let is_subcommand = args
+            .get(1)
+            .is_some_and(|arg| command.find_subcommand(arg).is_some());
+        if is_subcommand {
+            return Ok(Self {
+                cli: Cli::try_parse_from(args)?,
+                settings: Vec::new(),
+            });
+        }
+
+        // Find which options were given on the command line, which take
+        // precedence over the rest. Errors are reported by the final parse.
+        let given = command
+            .clone()
+            .ignore_errors(true)
+            .try_get_matches_from(&args)
+            .unwrap_or_default();
+        let path = match given.get_one::<PathBuf>("config") {
+            Some(path) => Some(path.clone()),
+            None => vars(&env_var("config"))
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+        };
+        let file = match &path {
+            Some(path) => load(path)?,
+            None => BTreeMap::new(),
+        };
+        let is_option = |key: &str| {
+            !NOT_IN_FILE.contains(&key)
+                && command
+                    .get_arguments()
+                    .any(|arg| arg.get_long() == Some(key))
+        };
+        if let Some(key) = file.keys().find(|key| !is_option(key)) {
+            return Err(Error::Unknown {
+                path: path.unwrap_or_default(),
+                key: key.clone(),
+            });
+        }
+
+        let mut layered = Vec::new();
+        let mut sources = Vec::new();
+        for arg in command.get_arguments() {
+            let Some(long) = arg.get_long() else {
+                continue;
+            };
+            let id = arg.get_id().as_str();
+            let flag = matches!(arg.get_action(), ArgAction::SetTrue);
+            let list = matches!(arg.get_action(), ArgAction::Append);
+            let var = env_var(long);
+            let invalid = |origin: Source| Error::InvalidValue {
+                key: long.to_owned(),
+                origin,
+            };
+
+            let source = if given.value_source(id) == Some(ValueSource::CommandLine) {
+                Source::CommandLine
+            } else if let Some(value) = vars(&var).filter(|value| !value.is_empty()) {
+                let origin = Source::Env(var);
+                if flag {
+                    match value.to_str() {
+                        Some("true" | "1") => push_arg(&mut layered, long, None),
+                        Some("false" | "0") => {}
+                        _ => return Err(invalid(origin)),
+                    }
+                } else if list {
+                    // One value per line
+                    let value = value.to_str().ok_or_else(|| invalid(origin.clone()))?;
+                    for value in value.lines() {
+                        push_arg(&mut layered, long, Some(OsStr::new(value)));
+                    }
+                } else {
+                    push_arg(&mut layered, long, Some(value.as_os_str()));
+                }
+                origin
+            } else if let Some(value) = file.get(long) {
+                let origin = Source::File(path.clone().unwrap_or_default());
+                match value {
+                    Value::Bool(true) if flag => push_arg(&mut layered, long, None),
+                    Value::Bool(false) if flag => {}
+                    _ if flag => return Err(invalid(origin)),
+                    Value::List(values) if list => {
+                        for value in values {
+                            let value = value.scalar().ok_or_else(|| invalid(origin.clone()))?;
+                            push_arg(&mut layered, long, Some(OsStr::new(&value)));
+                        }
+                    }
+                    value => {
+                        let value = value.scalar().ok_or_else(|| invalid(origin.clone()))?;
+                        push_arg(&mut layered, long, Some(OsStr::new(&value)));
+                    }
+                }
+                origin
+            } else {
+                Source::Default
+            };
+            sources.push((id.to_owned(), long.to_owned(), source, flag, list));
+        }
+
+        // Layered options go before the user's, which may end in a command
+        let argv = args
+            .iter()
+            .take(1)
+            .chain(&layered)
+            .chain(args.iter().skip(1));
+        let matches = command
+            .args_override_self(true)
+            .try_get_matches_from(argv)?;
+        let cli = Cli::from_arg_matches(&matches).map_err(|err| err.format(&mut Cli::command()))?;
+        let settings = sources
+            .into_iter()
+            .filter_map(|(id, name, source, flag, list)| {
+                let values = matches
+                    .get_raw(&id)?
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .collect();
+                Some(Setting {
+                    name,
+                    values,
+                    source,
+                    flag,
+                    list,
+                })
+            })
+            .collect();
+        Ok(Self { cli, settings })
+    }
+}
+
+/// The effective configuration, as a YAML config file with the source of each
+/// option in a comment.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for setting in &self.settings {
+            let value = if setting.list {
+                serde_json::Value::from(setting.values.clone())
+            } else {
+                let value = setting.values.first().cloned().unwrap_or_default();
+                if setting.flag {
+                    serde_json::Value::Bool(value == "true")
+                } else {
+                    serde_json::Value::String(value)
+                }
+            };
+            writeln!(f, "{}: {}  # {}", setting.name, value, setting.source)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+    use crate::Nproc;
+
+    fn vars<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<OsString> + 'a {
+        |var| {
+            vars.iter()
+                .find(|(k, _)| *k == var)
+                .map(|(_, v)| OsString::from(v))
+        }
+    }
+
+    fn source<'a>(config: &'a Config, name: &str) -> &'a Source {
+        &config
+            .settings
+            .iter()
+            .find(|setting| setting.name == name)
+            .unwrap()
+            .source
+    }
+
+    #[test]
+    fn test_layers() {
+        let tmpdir = tempdir::TempDir::new("config-test").unwrap();
+        let path = tmpdir.path().join("config.toml");
+        fs::write(
+            &path,
+            "nproc = 2\nenv_dir = \"/mnt/env\"\nevents = true\nport = 6000\nenv = [\"A={rank}\", \"B=1\"]\n",
+        )
+        .unwrap();
+        let config_var = path.to_str().unwrap();
+
+        let config = Config::try_parse_from(
+            ["pmi-k8s", "--port=7000"],
+            vars(&[("PMI_K8S_CONFIG", config_var), ("PMI_K8S_NPROC", "4")]),
+        )
+        .unwrap();
+        assert_eq!(config.cli.nproc, Some(Nproc::Count(4)));
+        assert_eq!(config.cli.port, 7000);
+        assert!(config.cli.events);
+        assert_eq!(config.cli.env_dir, Some(PathBuf::from("/mnt/env")));
+        assert_eq!(config.cli.env.len(), 2);
+        assert_eq!(
+            source(&config, "nproc"),
+            &Source::Env("PMI_K8S_NPROC".into())
+        );
+        assert_eq!(source(&config, "port"), &Source::CommandLine);
+        assert_eq!(source(&config, "events"), &Source::File(path.clone()));
+        assert_eq!(source(&config, "log-format"), &Source::Default);
+
+        let config = Config::try_parse_from(
+            ["pmi-k8s", "--config", config_var, "--env=C=2", "--", "foo"],
+            vars(&[("PMI_K8S_EVENTS", "false")]),
+        )
+        .unwrap();
+        assert_eq!(config.cli.nproc, Some(Nproc::Count(2)));
+        assert!(!config.cli.events);
+        assert_eq!(config.cli.env.len(), 1);
+        assert_eq!(config.cli.command, Some("foo".to_owned()));
+
+        let printed = config.to_string();
+        assert!(printed.contains("nproc: \"2\"  # from file"));
+        assert!(printed.contains("events: false  # from env PMI_K8S_EVENTS\n"));
+        assert!(printed.contains("env: [\"C=2\"]  # from command line\n"));
+    }
+
+    #[test]
+    fn test_yaml() {
+        let tmpdir = tempdir::TempDir::new("config-test").unwrap();
+        let path = tmpdir.path().join("config.yaml");
+        fs::write(&path, "nproc: 2\nenv-default:\n  - A=1\n").unwrap();
+        let args = ["pmi-k8s", "--config", path.to_str().unwrap()];
+        let config = Config::try_parse_from(args, vars(&[])).unwrap();
+        assert_eq!(config.cli.nproc, Some(Nproc::Count(2)));
+        assert_eq!(config.cli.env_default.len(), 1);
+
+        fs::write(&path, "nproc: 2\nbogus: 1\n").unwrap();
+        assert!(matches!(
+            Config::try_parse_from(args, vars(&[])),
+            Err(Error::Unknown { .. })
+        ));
+        fs::write(&path, "nproc: [2]\n").unwrap();
+        assert!(matches!(
+            Config::try_parse_from(args, vars(&[])),
+            Err(Error::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn test_subcommand() {
+        let config = Config::try_parse_from(
+            ["pmi-k8s", "exec", "--env-dir=/mnt/env", "--", "foo"],
+            vars(&[("PMI_K8S_NPROC", "4")]),
+        )
+        .unwrap();
+        assert!(config.cli.subcommand.is_some());
+        assert!(config.settings.is_empty());
+    }
+}
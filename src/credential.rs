@@ -0,0 +1,378 @@
+//! Credentials for client processes, issued by `PMIx_Get_credential` and
+//! checked by `PMIx_Validate_credential`, so processes can prove their identity
+//! to other services.
+//!
+//! A credential holds the process' namespace and rank, and when it expires,
+//! signed with an HMAC. If the job has a shared secret, every pod derives the
+//! same key from it and can validate any credential. Otherwise, each pod signs
+//! with its own random key, and credentials for processes on other pods are
+//! sent to that pod to be validated.
+
+use std::pin::pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{io, mem, net::SocketAddr};
+
+use futures::{StreamExt, future::select};
+use hmac::{Hmac, Mac};
+use metrics::counter;
+use sha2::Sha256;
+use tokio::{net, sync::mpsc, time};
+use tokio_stream::wrappers::{TcpListenerStream, UnboundedReceiverStream};
+use tracing::{Span, debug, field, instrument, warn};
+
+use crate::pmix::ProcDisplay;
+use crate::{
+    ModexError,
+    peer::{Endpoint, PeerDiscovery},
+    pmix::{
+        char_to_u8,
+        globals::{self, CredentialEvent},
+        sys, u8_to_char,
+    },
+};
+use crate::{net::Backoff, telemetry, tls::Tls, wire};
+
+/// How long credentials are valid for after they are issued.
+pub const LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait for the pod hosting a process to validate its credential.
+/// Peers are normally known already, so this only bounds lookups of pods that
+/// are gone.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const MAC_LEN: usize = 32;
+const PROC_LEN: usize = mem::size_of::<sys::pmix_proc_t>();
+const PAYLOAD_LEN: usize = 1 + PROC_LEN + mem::size_of::<u64>();
+const STATUS_LEN: usize = mem::size_of::<sys::pmix_status_t>();
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Issues and verifies credentials with a single key.
+struct Signer {
+    key: [u8; KEY_LEN],
+    /// Whether every pod in the job has the same key.
+    shared: bool,
+}
+
+impl Signer {
+    fn random() -> Result<Self, io::Error> {
+        let mut key = [0; KEY_LEN];
+        getrandom::fill(&mut key)?;
+        Ok(Self { key, shared: false })
+    }
+
+    fn shared(secret: &wire::Secret) -> Self {
+        Self {
+            key: secret.derive(b"pmi-k8s credential"),
+            shared: true,
+        }
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        #[allow(clippy::unwrap_used, reason = "HMAC accepts keys of any length")]
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(payload);
+        mac
+    }
+
+    fn sign(&self, proc: &sys::pmix_proc_t, expires: u64) -> Vec<u8> {
+        let mut credential = Vec::with_capacity(PAYLOAD_LEN + MAC_LEN);
+        credential.push(VERSION);
+        credential.extend_from_slice(char_to_u8(&proc.nspace));
+        credential.extend_from_slice(&proc.rank.to_be_bytes());
+        credential.extend_from_slice(&expires.to_be_bytes());
+        let mac = self.mac(&credential).finalize().into_bytes();
+        credential.extend_from_slice(&mac);
+        credential
+    }
+
+    fn issue(&self, proc: &sys::pmix_proc_t) -> Vec<u8> {
+        self.sign(proc, now() + LIFETIME.as_secs())
+    }
+
+    /// The process a credential was issued to, if it is well-formed and has
+    /// not expired. This does not check the signature.
+    fn parse(credential: &[u8]) -> Option<sys::pmix_proc_t> {
+        if credential.len() != PAYLOAD_LEN + MAC_LEN || credential[0] != VERSION {
+            return None;
+        }
+        let (nspace, rest) = credential[1..].split_at(mem::size_of::<sys::pmix_nspace_t>());
+        let (rank, rest) = rest.split_at(mem::size_of::<sys::pmix_rank_t>());
+        #[allow(clippy::unwrap_used, reason = "Sizes are statically known")]
+        let expires = u64::from_be_bytes(rest[..mem::size_of::<u64>()].try_into().unwrap());
+        if expires < now() {
+            return None;
+        }
+        #[allow(clippy::unwrap_used, reason = "Sizes are statically known")]
+        Some(sys::pmix_proc_t {
+            nspace: u8_to_char(nspace).try_into().unwrap(),
+            rank: u32::from_be_bytes(rank.try_into().unwrap()),
+        })
+    }
+
+    /// Whether `credential` is current, and was signed with this key.
+    fn verify(&self, credential: &[u8]) -> bool {
+        Self::parse(credential).is_some()
+            && self
+                .mac(&credential[..PAYLOAD_LEN])
+                .verify_slice(&credential[PAYLOAD_LEN..])
+                .is_ok()
+    }
+}
+
+pub struct NetCredentials<'a, D> {
+    listener: net::TcpListener,
+    discovery: &'a D,
+    signer: Signer,
+    wire: wire::Options,
+}
+
+impl<'a, D: PeerDiscovery> NetCredentials<'a, D> {
+    pub async fn new(addr: SocketAddr, discovery: &'a D) -> Result<Self, ModexError<D::Error>> {
+        let listener = net::TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener,
+            discovery,
+            signer: Signer::random()?,
+            wire: Default::default(),
+        })
+    }
+
+    /// Authenticate connections to and from peers with `secret`, and sign
+    /// credentials with a key derived from it.
+    pub fn with_secret(mut self, secret: wire::Secret) -> Self {
+        self.signer = Signer::shared(&secret);
+        self.wire.secret = Some(secret);
+        self
+    }
+
+    /// Encrypt connections to and from peers with `tls`.
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.wire.tls = Some(tls);
+        self
+    }
+
+    /// Retry connecting to peers that aren't listening yet with `backoff`.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.wire.backoff = backoff;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        #[allow(clippy::unwrap_used, reason = "We know we have a socket bound")]
+        self.listener.local_addr().unwrap()
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "credential_validate",
+        skip_all,
+        fields(proc = field::Empty, peer = field::Empty),
+    )]
+    async fn validate(
+        discovery: &'a D,
+        signer: &Signer,
+        options: &wire::Options,
+        credential: &[u8],
+    ) -> Result<bool, ModexError<D::Error>> {
+        let Some(proc) = Signer::parse(credential) else {
+            return Ok(false);
+        };
+        Span::current().record("proc", field::display(ProcDisplay(&proc)));
+        if signer.verify(credential) {
+            return Ok(true);
+        } else if signer.shared {
+            return Ok(false);
+        }
+
+        // Don't look up processes that can't exist
+        if !discovery.contains(&proc) {
+            return Ok(false);
+        }
+
+        // Only the pod hosting the process knows the key it was signed with
+        let addr = time::timeout(PEER_TIMEOUT, discovery.peer(&proc, Endpoint::Credential))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out finding peer"))?
+            .map_err(ModexError::Peer)?;
+        Span::current().record("peer", field::display(&addr));
+        let mut s = wire::connect(&addr, Endpoint::Credential, options).await?;
+        wire::write_frame(&mut s, credential).await?;
+        let status = wire::read_frame(&mut s)
+            .await?
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid response"))?;
+        let status = sys::pmix_status_t::from_be_bytes(status);
+        debug!(status, "peer validated credential");
+        Ok(status == sys::PMIX_SUCCESS as sys::pmix_status_t)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "credential_respond",
+        skip_all,
+        fields(peer = ?c.peer_addr().ok()),
+    )]
+    async fn respond(
+        c: net::TcpStream,
+        signer: &Signer,
+        options: &wire::Options,
+    ) -> Result<(), ModexError<D::Error>> {
+        let mut c = wire::accept(c, Endpoint::Credential, options).await?;
+        let credential = wire::read_frame(&mut c).await?;
+        let status = if signer.verify(&credential) {
+            sys::PMIX_SUCCESS as sys::pmix_status_t
+        } else {
+            sys::PMIX_ERR_INVALID_CRED
+        };
+        let response: [u8; STATUS_LEN] = status.to_be_bytes();
+        wire::write_frame(&mut c, &response).await?;
+        Ok(())
+    }
+
+    async fn handle(
+        discovery: &'a D,
+        signer: &Signer,
+        options: &wire::Options,
+        event: CredentialEvent,
+    ) {
+        match event {
+            CredentialEvent::Get { proc, cb } => {
+                debug!(proc = %ProcDisplay(&proc), "issuing credential");
+                counter!(telemetry::CREDENTIALS_ISSUED).increment(1);
+                cb.call(sys::PMIX_SUCCESS as sys::pmix_status_t, signer.issue(&proc));
+            }
+            CredentialEvent::Validate {
+                proc,
+                credential,
+                cb,
+            } => {
+                let result = Self::validate(discovery, signer, options, &credential).await;
+                let (status, result) = match result {
+                    Ok(true) => (sys::PMIX_SUCCESS as sys::pmix_status_t, "valid"),
+                    Ok(false) => (sys::PMIX_ERR_INVALID_CRED, "invalid"),
+                    Err(err) => {
+                        warn!(%err, requester = %ProcDisplay(&proc), "validating credential");
+                        (sys::PMIX_ERROR, "error")
+                    }
+                };
+                counter!(telemetry::CREDENTIALS_VALIDATED, "result" => result).increment(1);
+                cb.call(status);
+            }
+        }
+    }
+
+    #[instrument(name = "credentials", skip_all, fields(addr = %self.addr()))]
+    pub async fn serve(
+        self,
+        events: mpsc::UnboundedReceiver<globals::CredentialEvent>,
+    ) -> Result<(), ModexError<D::Error>> {
+        let Self {
+            listener,
+            discovery,
+            signer,
+            wire,
+        } = self;
+        let requests = UnboundedReceiverStream::new(events).for_each_concurrent(8, async |e| {
+            Self::handle(discovery, &signer, &wire, e).await
+        });
+        let responses =
+            TcpListenerStream::new(listener).for_each_concurrent(8, async |c| match c {
+                Ok(c) => Self::respond(c, &signer, &wire)
+                    .await
+                    .unwrap_or_else(|err| warn!(%err, "credential response")),
+                Err(err) => warn!(%err, "credential accept"),
+            });
+
+        // Stop once the PMIx server is finalized
+        select(pin!(requests), pin!(responses)).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::panic)]
+    use crate::peer::DirectoryPeers;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use futures::future::{Either, select};
+    use tempdir::TempDir;
+
+    fn proc(rank: u32) -> sys::pmix_proc_t {
+        let mut proc = sys::pmix_proc_t {
+            nspace: [0; _],
+            rank,
+        };
+        proc.nspace[..3].copy_from_slice(u8_to_char(b"foo"));
+        proc
+    }
+
+    #[test]
+    fn test_signer() {
+        let signer = Signer::random().unwrap();
+        let credential = signer.issue(&proc(3));
+        assert!(signer.verify(&credential));
+        assert_eq!(Signer::parse(&credential).unwrap().rank, 3);
+
+        let mut forged = credential.clone();
+        forged[PAYLOAD_LEN - 9] ^= 1; // Change the rank
+        assert!(!signer.verify(&forged));
+        assert!(!signer.verify(&credential[1..]));
+        assert!(!signer.verify(&signer.sign(&proc(3), now() - 1)));
+        assert!(!Signer::random().unwrap().verify(&credential));
+
+        let secret = wire::Secret::new(b"foo".as_slice());
+        let credential = Signer::shared(&secret).issue(&proc(3));
+        assert!(Signer::shared(&secret).verify(&credential));
+    }
+
+    #[tokio::test]
+    async fn test_validate_remote() {
+        let nproc = 4;
+
+        let tmpdir = TempDir::new("credential-test").unwrap();
+        let discovery = DirectoryPeers::new(tmpdir.path(), nproc, 2);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let requester = NetCredentials::new(addr, &discovery).await.unwrap();
+        discovery.register(&requester.addr()).unwrap();
+        let responder = NetCredentials::new(addr, &discovery).await.unwrap();
+        discovery.register(&responder.addr()).unwrap();
+
+        // Issued on the responder's node, which is the only one with its key
+        let credential = responder.signer.issue(&proc(nproc as u32));
+        let forged = Signer::random().unwrap().issue(&proc(nproc as u32));
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let validate = async {
+            let options = wire::Options::default();
+            let valid =
+                NetCredentials::validate(&discovery, &requester.signer, &options, &credential);
+            let forged = NetCredentials::validate(&discovery, &requester.signer, &options, &forged);
+            (valid.await.unwrap(), forged.await.unwrap())
+        };
+        let Either::Left(((valid, forged), _)) =
+            select(pin!(validate), pin!(responder.serve(rx))).await
+        else {
+            panic!("expected validation");
+        };
+        assert!(valid);
+        assert!(!forged);
+
+        // Processes beyond the job's size aren't looked up, which would wait
+        // for a pod that never appears
+        let options = wire::Options::default();
+        for rank in [2 * nproc as u32, sys::PMIX_RANK_WILDCARD] {
+            let unknown = Signer::random().unwrap().issue(&proc(rank));
+            let valid = NetCredentials::validate(&discovery, &requester.signer, &options, &unknown);
+            assert!(!valid.await.unwrap());
+        }
+    }
+}
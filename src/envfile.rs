@@ -0,0 +1,233 @@
+//! Environment files, as written by a `pmi-k8s` sidecar for other containers
+//! to start processes from.
+
+use std::{
+    ffi, io,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// How environment files are written, with `--env-dir`.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnvFormat {
+    /// `KEY='value'` lines, for `source` in a POSIX shell
+    #[default]
+    Shell,
+    /// Unquoted `KEY=value` lines, for `docker run --env-file`
+    #[value(name = "env-file")]
+    EnvFile,
+    /// A JSON object
+    Json,
+    /// NUL-terminated `KEY=value` entries, as printed by `env -0`
+    Nul,
+}
+
+impl EnvFormat {
+    /// The extension of files written in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Shell | Self::EnvFile => "env",
+            Self::Json => "json",
+            Self::Nul => "env0",
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn is_identifier(key: &[u8]) -> bool {
+    key.first().is_some_and(|c| !c.is_ascii_digit())
+        && key.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+}
+
+/// Encode environment variables in `format`. Values are written byte for
+/// byte, except in JSON, where values that aren't valid UTF-8 are written as
+/// arrays of bytes.
+pub fn encode<'a>(
+    vars: impl IntoIterator<Item = (&'a ffi::OsStr, &'a ffi::OsStr)>,
+    format: EnvFormat,
+) -> Result<Vec<u8>, io::Error> {
+    let mut out = Vec::new();
+    let mut json = serde_json::Map::new();
+    for (k, v) in vars {
+        let (k, v) = (k.as_bytes(), v.as_bytes());
+        if matches!(format, EnvFormat::Shell | EnvFormat::EnvFile) && !is_identifier(k) {
+            return Err(invalid(format!(
+                "invalid variable name '{}'",
+                k.escape_ascii()
+            )));
+        }
+        match format {
+            EnvFormat::Shell => {
+                out.extend_from_slice(k);
+                out.extend_from_slice(b"='");
+                for c in v {
+                    match c {
+                        b'\'' => out.extend_from_slice(br"'\''"),
+                        c => out.push(*c),
+                    }
+                }
+                out.extend_from_slice(b"'\n");
+            }
+            EnvFormat::EnvFile => {
+                // Values are taken literally up to the end of the line
+                if v.contains(&b'\n') {
+                    return Err(invalid(format!(
+                        "value of {} contains a newline",
+                        k.escape_ascii()
+                    )));
+                }
+                out.extend_from_slice(k);
+                out.push(b'=');
+                out.extend_from_slice(v);
+                out.push(b'\n');
+            }
+            EnvFormat::Json => {
+                let k = String::from_utf8(k.to_vec()).map_err(|_| {
+                    invalid(format!("invalid variable name '{}'", k.escape_ascii()))
+                })?;
+                let v = match String::from_utf8(v.to_vec()) {
+                    Ok(v) => serde_json::Value::from(v),
+                    Err(err) => serde_json::Value::from(err.into_bytes()),
+                };
+                json.insert(k, v);
+            }
+            EnvFormat::Nul => {
+                out.extend_from_slice(k);
+                out.push(b'=');
+                out.extend_from_slice(v);
+                out.push(b'\0');
+            }
+        }
+    }
+    if let EnvFormat::Json = format {
+        serde_json::to_writer(&mut out, &json)?;
+        out.push(b'\n');
+    }
+    Ok(out)
+}
+
+fn split_once(s: &[u8], c: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().position(|x| *x == c)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+/// Decode environment variables written by [`encode`] in `format`.
+pub fn decode(
+    data: &[u8],
+    format: EnvFormat,
+) -> Result<Vec<(ffi::OsString, ffi::OsString)>, io::Error> {
+    let malformed = || invalid(format!("malformed {:?} environment", format));
+    let entry = |entry: &[u8]| {
+        let (k, v) = split_once(entry, b'=').ok_or_else(malformed)?;
+        Ok::<_, io::Error>((
+            ffi::OsString::from_vec(k.to_vec()),
+            ffi::OsString::from_vec(v.to_vec()),
+        ))
+    };
+
+    match format {
+        EnvFormat::Shell => {
+            let mut vars = Vec::new();
+            let mut rest = data;
+            while !rest.is_empty() {
+                let (k, tail) = split_once(rest, b'=').ok_or_else(malformed)?;
+                let mut tail = tail.strip_prefix(b"'").ok_or_else(malformed)?;
+                let mut v = Vec::new();
+                loop {
+                    let (part, after) = split_once(tail, b'\'').ok_or_else(malformed)?;
+                    v.extend_from_slice(part);
+                    match after.strip_prefix(br"\''") {
+                        Some(after) => {
+                            v.push(b'\'');
+                            tail = after;
+                        }
+                        None => {
+                            rest = after.strip_prefix(b"\n").ok_or_else(malformed)?;
+                            break;
+                        }
+                    }
+                }
+                vars.push((
+                    ffi::OsString::from_vec(k.to_vec()),
+                    ffi::OsString::from_vec(v),
+                ));
+            }
+            Ok(vars)
+        }
+        EnvFormat::EnvFile => data
+            .split(|c| *c == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(entry)
+            .collect(),
+        EnvFormat::Nul => data
+            .split(|c| *c == b'\0')
+            .filter(|entry| !entry.is_empty())
+            .map(entry)
+            .collect(),
+        EnvFormat::Json => {
+            let json: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(data)?;
+            json.into_iter()
+                .map(|(k, v)| {
+                    let v = match v {
+                        serde_json::Value::String(v) => v.into_bytes(),
+                        v => serde_json::from_value::<Vec<u8>>(v)?,
+                    };
+                    Ok::<_, io::Error>((k.into(), ffi::OsString::from_vec(v)))
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let kvs = [
+            ("FOO", b"it's".as_slice()),
+            ("BAR", b"a\xffb".as_slice()),
+            ("BAZ", b"".as_slice()),
+        ];
+        let vars = kvs.map(|(k, v)| (ffi::OsStr::new(k), ffi::OsStr::from_bytes(v)));
+
+        let shell = encode(vars, EnvFormat::Shell).unwrap();
+        assert_eq!(shell, b"FOO='it'\\''s'\nBAR='a\xffb'\nBAZ=''\n");
+        let env_file = encode(vars, EnvFormat::EnvFile).unwrap();
+        assert_eq!(env_file, b"FOO=it's\nBAR=a\xffb\nBAZ=\n");
+        let json = encode(vars, EnvFormat::Json).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({"FOO": "it's", "BAR": [97, 255, 98], "BAZ": ""}),
+        );
+        let nul = encode(vars, EnvFormat::Nul).unwrap();
+        assert_eq!(nul, b"FOO=it's\0BAR=a\xffb\0BAZ=\0");
+
+        for format in [
+            EnvFormat::Shell,
+            EnvFormat::EnvFile,
+            EnvFormat::Json,
+            EnvFormat::Nul,
+        ] {
+            let mut decoded = decode(&encode(vars, format).unwrap(), format).unwrap();
+            decoded.sort();
+            let mut expected = vars.map(|(k, v)| (k.to_owned(), v.to_owned()));
+            expected.sort();
+            assert_eq!(decoded, expected, "{:?}", format);
+        }
+        assert!(decode(b"FOO='bar", EnvFormat::Shell).is_err());
+
+        let newline = [(ffi::OsStr::new("FOO"), ffi::OsStr::new("a\nb"))];
+        assert!(encode(newline, EnvFormat::EnvFile).is_err());
+        let invalid = [(ffi::OsStr::new("1FOO"), ffi::OsStr::new(""))];
+        assert!(encode(invalid, EnvFormat::Shell).is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! Environment files written by a `pmi-k8s` sidecar, and starting processes
+//! from them for `pmi-k8s exec`.
+
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    pin::pin,
+    process::ExitStatus,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use futures::{
+    StreamExt, TryStreamExt,
+    future::{self, Either},
+    stream::{self, FuturesUnordered},
+};
+use nix::{
+    errno::Errno,
+    sys::signal::{Signal, kill},
+    unistd::Pid,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    process::Command,
+    signal::unix::{SignalKind, signal},
+    time,
+};
+use tracing::{debug, info};
+
+use crate::envfile::{self, EnvFormat};
+
+/// Written to the environment directory once all environment files and the
+/// manifest are complete. Holds the generation they belong to.
+pub const READY_FILE: &str = "ready";
+
+/// Describes the environment files, see [`Manifest`].
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Holds the generation of the last environment files published, and is kept
+/// across restarts of the sidecar.
+pub const GENERATION_FILE: &str = "generation";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Signals received by `exec` that are passed on to every process.
+const FORWARDED: [Signal; 6] = [
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGQUIT,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+];
+
+/// Options of `pmi-k8s exec`.
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    #[arg(long)]
+    pub env_dir: PathBuf,
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    pub command: Vec<OsString>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable to read {0}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("invalid manifest {0}")]
+    Manifest(PathBuf, #[source] serde_json::Error),
+    #[error("invalid environment file {0}")]
+    Decode(PathBuf, #[source] io::Error),
+    #[error("no environment files in {0}")]
+    Empty(PathBuf),
+    #[error("unable to start {0:?}")]
+    Spawn(OsString, #[source] io::Error),
+    #[error("unable to wait for process")]
+    Wait(#[source] io::Error),
+    #[error("unable to forward signals")]
+    Signal(#[source] io::Error),
+}
+
+/// The set of environment files published by one run of the sidecar.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// Incremented every time the sidecar publishes, so consumers can tell
+    /// when it has restarted.
+    pub generation: u64,
+    /// The PMIx namespace of the processes.
+    pub namespace: String,
+    pub format: EnvFormat,
+    pub ranks: Vec<RankFile>,
+}
+
+/// The environment file for a process, relative to the manifest.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct RankFile {
+    pub rank: u32,
+    pub file: String,
+}
+
+/// Write `data` to `name` in `dir`, such that readers see either the old file
+/// or the complete new one.
+async fn write_atomic(dir: &Path, name: &str, data: &[u8]) -> Result<(), io::Error> {
+    let tmp = dir.join(format!(".{}.tmp", name));
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, dir.join(name)).await
+}
+
+/// Increment the generation stored in `dir`, returning the new value.
+async fn next_generation(dir: &Path) -> Result<u64, io::Error> {
+    let generation = match fs::read_to_string(dir.join(GENERATION_FILE)).await {
+        Ok(generation) => generation
+            .trim()
+            .parse::<u64>()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err),
+    } + 1;
+    write_atomic(dir, GENERATION_FILE, generation.to_string().as_bytes()).await?;
+    Ok(generation)
+}
+
+/// Publish the encoded environment of each rank to `dir`, then the manifest,
+/// and finally the ready marker. Every file is replaced atomically.
+pub async fn publish(
+    dir: &Path,
+    namespace: String,
+    format: EnvFormat,
+    envs: impl IntoIterator<Item = (u32, Vec<u8>)>,
+) -> Result<Manifest, io::Error> {
+    let generation = next_generation(dir).await?;
+    let mut ranks = Vec::new();
+    for (i, (rank, data)) in envs.into_iter().enumerate() {
+        let file = format!("{}.{}", i, format.extension());
+        write_atomic(dir, &file, &data).await?;
+        ranks.push(RankFile { rank, file });
+    }
+    let manifest = Manifest {
+        generation,
+        namespace,
+        format,
+        ranks,
+    };
+    write_atomic(dir, MANIFEST_FILE, &serde_json::to_vec(&manifest)?).await?;
+    write_atomic(dir, READY_FILE, generation.to_string().as_bytes()).await?;
+    Ok(manifest)
+}
+
+/// Whether `name` is a file published to (or being written to) the
+/// environment directory, which must not outlive the sidecar.
+fn is_published(name: &str) -> bool {
+    if name == READY_FILE || name == MANIFEST_FILE {
+        return true;
+    }
+    if name.starts_with('.') && name.ends_with(".tmp") {
+        return true;
+    }
+    let Some((stem, extension)) = name.split_once('.') else {
+        return false;
+    };
+    stem.parse::<u32>().is_ok()
+        && EnvFormat::value_variants()
+            .iter()
+            .any(|format| format.extension() == extension)
+}
+
+/// Remove the ready marker, manifest and environment files from `dir`, so
+/// nothing starts from a stale environment. The generation is kept.
+pub async fn clean(dir: &Path) -> Result<(), io::Error> {
+    match fs::remove_file(dir.join(READY_FILE)).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_str().is_some_and(is_published) {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the sidecar to publish environment files to `dir`, and read their
+/// manifest.
+pub async fn wait_ready(dir: &Path) -> Result<Manifest, Error> {
+    let ready = dir.join(READY_FILE);
+    let path = dir.join(MANIFEST_FILE);
+    loop {
+        let generation = match fs::read_to_string(&ready).await {
+            Ok(generation) => generation.trim().parse::<u64>().ok(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(Error::Read(ready, err)),
+        };
+        if let Some(generation) = generation {
+            let data = fs::read(&path)
+                .await
+                .map_err(|err| Error::Read(path.clone(), err))?;
+            let manifest = serde_json::from_slice::<Manifest>(&data)
+                .map_err(|err| Error::Manifest(path.clone(), err))?;
+            // Otherwise the sidecar restarted while we were reading
+            if manifest.generation == generation {
+                return Ok(manifest);
+            }
+        }
+        time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// The exit code a shell would report for `status`.
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+/// Run `command` once for each environment file in `dir`, with that
+/// environment added to our own. Returns zero if every process succeeded,
+/// otherwise the exit code of the first to fail.
+pub async fn run(dir: &Path, command: &OsStr, args: &[OsString]) -> Result<i32, Error> {
+    // Register handlers before starting processes, so no signal is missed
+    let signals = FORWARDED
+        .into_iter()
+        .map(|sig| Ok((signal(SignalKind::from_raw(sig as i32))?, sig)))
+        .collect::<Result<Vec<_>, io::Error>>()
+        .map_err(Error::Signal)?;
+
+    let manifest = wait_ready(dir).await?;
+    info!(
+        generation = manifest.generation,
+        namespace = %manifest.namespace,
+        "environment files ready"
+    );
+    if manifest.ranks.is_empty() {
+        return Err(Error::Empty(dir.to_owned()));
+    }
+
+    let mut children = Vec::new();
+    for RankFile { file, .. } in manifest.ranks {
+        let file = dir.join(file);
+        let data = fs::read(&file)
+            .await
+            .map_err(|err| Error::Read(file.clone(), err))?;
+        let vars = envfile::decode(&data, manifest.format)
+            .map_err(|err| Error::Decode(file.clone(), err))?;
+        let child = Command::new(command)
+            .args(args)
+            .envs(vars)
+            .spawn()
+            .map_err(|err| Error::Spawn(command.to_owned(), err))?;
+        debug!(file = %file.display(), pid = child.id(), "started process");
+        children.push(child);
+    }
+    let pids = children
+        .iter()
+        .filter_map(|child| child.id())
+        .map(|pid| Pid::from_raw(pid as i32))
+        .collect::<Vec<_>>();
+
+    let forward = pin!(async {
+        let mut signals = stream::select_all(signals.into_iter().map(|(s, sig)| {
+            stream::unfold(
+                s,
+                move |mut s| async move { s.recv().await.map(|()| (sig, s)) },
+            )
+        }));
+        while let Some(sig) = signals.next().await {
+            info!(signal = %sig, "forwarding signal");
+            for pid in &pids {
+                // The process may have exited already
+                match kill(*pid, sig) {
+                    Ok(()) | Err(Errno::ESRCH) => {}
+                    Err(err) => return Err(Error::Signal(err.into())),
+                }
+            }
+        }
+        Ok(())
+    });
+    let wait = pin!(async {
+        let mut code = 0;
+        let mut statuses = children
+            .iter_mut()
+            .map(|child| child.wait())
+            .collect::<FuturesUnordered<_>>();
+        while let Some(status) = statuses.try_next().await.map_err(Error::Wait)? {
+            if code == 0 {
+                code = exit_code(status);
+            }
+        }
+        Ok(code)
+    });
+
+    match future::select(wait, forward).await {
+        Either::Left((code, _)) => code,
+        Either::Right((Ok(()), wait)) => wait.await,
+        Either::Right((Err(err), _)) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run() {
+        let tmpdir = tempdir::TempDir::new("exec-test").unwrap();
+        let envs = [(4, "0"), (5, "3")].map(|(rank, code)| {
+            let vars = [(OsStr::new("CODE"), OsStr::new(code))];
+            (rank, envfile::encode(vars, EnvFormat::Shell).unwrap())
+        });
+
+        let args = ["-c".into(), "exit $CODE".into()];
+        let run = run(tmpdir.path(), OsStr::new("sh"), &args);
+        let mut run = pin!(run);
+        // Not ready yet
+        assert!(
+            time::timeout(Duration::from_millis(200), &mut run)
+                .await
+                .is_err()
+        );
+        publish(tmpdir.path(), "foo".into(), EnvFormat::Shell, envs)
+            .await
+            .unwrap();
+        assert_eq!(run.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let tmpdir = tempdir::TempDir::new("exec-test").unwrap();
+        let envs = || [(4, b"FOO=bar\0".to_vec()), (5, b"FOO=baz\0".to_vec())];
+        let manifest = publish(tmpdir.path(), "foo".into(), EnvFormat::Nul, envs())
+            .await
+            .unwrap();
+        assert_eq!(manifest.generation, 1);
+        assert_eq!(manifest.ranks[1].rank, 5);
+        assert_eq!(manifest.ranks[1].file, "1.env0");
+        assert_eq!(wait_ready(tmpdir.path()).await.unwrap(), manifest);
+
+        // A restarted sidecar publishes a new generation
+        clean(tmpdir.path()).await.unwrap();
+        let manifest = publish(tmpdir.path(), "foo".into(), EnvFormat::Nul, envs())
+            .await
+            .unwrap();
+        assert_eq!(manifest.generation, 2);
+        assert_eq!(wait_ready(tmpdir.path()).await.unwrap(), manifest);
+    }
+
+    #[tokio::test]
+    async fn test_clean() {
+        let tmpdir = tempdir::TempDir::new("exec-test").unwrap();
+        let names = [
+            READY_FILE,
+            MANIFEST_FILE,
+            GENERATION_FILE,
+            ".0.env.tmp",
+            "0.env",
+            "1.json",
+            "2.env0",
+            "foo.env",
+            "other",
+        ];
+        for name in names {
+            std::fs::write(tmpdir.path().join(name), "").unwrap();
+        }
+        clean(tmpdir.path()).await.unwrap();
+
+        let mut remaining = std::fs::read_dir(tmpdir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(remaining, ["foo.env", GENERATION_FILE, "other"]);
+        // Nothing to clean
+        clean(tmpdir.path()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty() {
+        let tmpdir = tempdir::TempDir::new("exec-test").unwrap();
+        publish(tmpdir.path(), "foo".into(), EnvFormat::Shell, [])
+            .await
+            .unwrap();
+        let run = run(tmpdir.path(), OsStr::new("true"), &[]).await;
+        assert!(matches!(run, Err(Error::Empty(_))));
+    }
+}
@@ -1,20 +1,22 @@
 use std::collections::hash_map::Entry;
-use std::collections::{BTreeSet, HashMap};
-use std::net::SocketAddr;
-use std::{io, mem};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use std::{fmt, io, mem};
 
 use futures::stream::FuturesUnordered;
 use futures::{FutureExt, select, stream};
 use futures::{StreamExt, TryStreamExt};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use metrics::{counter, histogram};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net;
-use tokio::sync::mpsc;
-use tracing::warn;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::{Instrument, Span, debug, debug_span, field, info, instrument, warn};
 
 use super::ModexError;
-use crate::net::connect_peer;
 use crate::peer::{Endpoint, PeerDiscovery};
-use crate::pmix::{char_to_u8, globals, sys, u8_to_char};
+use crate::pmix::{ProcDisplay, char_to_u8, globals, sys, u8_to_char};
+use crate::{net::Backoff, telemetry, tls::Tls, wire};
 
 type Sequence = u32;
 type Participants = BTreeSet<sys::pmix_proc_t>;
@@ -22,23 +24,50 @@ type Participants = BTreeSet<sys::pmix_proc_t>;
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct FenceId(Participants, Sequence);
 
+impl fmt::Display for FenceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only show the first few participants, fences may include every rank.
+        const MAX_SHOWN: usize = 4;
+        let FenceId(participants, seq) = self;
+        write!(f, "[")?;
+        for (i, proc) in participants.iter().take(MAX_SHOWN).enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", ProcDisplay(proc))?;
+        }
+        if participants.len() > MAX_SHOWN {
+            write!(f, ",... ({} total)", participants.len())?;
+        }
+        write!(f, "]#{}", seq)
+    }
+}
+
+/// The addresses of a fence's peers, by node rank.
+type NodeAddrs = BTreeMap<u32, IpAddr>;
+
 #[derive(Default)]
 struct FenceAcc {
     complete: usize,
     data: Vec<u8>,
     cb: Option<globals::ModexCallback>,
     expected: Option<usize>,
+    peers: NodeAddrs,
+    started: Option<Instant>,
+    arrivals: Vec<(IpAddr, Instant)>,
 }
 
 impl FenceAcc {
     fn update(&mut self, data: FenceData) {
         match data {
-            FenceData::Local(npeers) => {
-                let _ = self.expected.insert(npeers);
+            FenceData::Local(peers) => {
+                let _ = self.expected.insert(peers.len());
+                self.peers = peers;
             }
-            FenceData::Remote(data) => {
+            FenceData::Remote(peer, data) => {
                 self.data.extend(data);
-                self.complete += 1
+                self.complete += 1;
+                self.arrivals.push((peer, Instant::now()));
             }
         };
     }
@@ -47,16 +76,40 @@ impl FenceAcc {
         if self.expected != Some(self.complete) {
             None
         } else if let Some(cb) = self.cb.take() {
+            self.record_metrics();
             Some((cb, mem::take(&mut self.data)))
         } else {
             None
         }
     }
+
+    fn record_metrics(&self) {
+        counter!(telemetry::FENCES_COMPLETED).increment(1);
+        histogram!(telemetry::FENCE_BYTES, "direction" => "received")
+            .record(self.data.len() as f64);
+        let Some(started) = self.started else {
+            return;
+        };
+        histogram!(telemetry::FENCE_DURATION).record(started.elapsed());
+        // Peers that arrive before the local processes enter the fence aren't
+        // holding it up, so count as zero. Peers aren't used as a label, as
+        // there can be thousands of them; the slowest is logged instead.
+        let waits = self
+            .arrivals
+            .iter()
+            .map(|(peer, arrived)| (peer, arrived.saturating_duration_since(started)));
+        for (_, wait) in waits.clone() {
+            histogram!(telemetry::FENCE_PEER_WAIT).record(wait);
+        }
+        if let Some((peer, wait)) = waits.max_by_key(|(_, wait)| *wait) {
+            debug!(%peer, ?wait, "slowest peer to arrive");
+        }
+    }
 }
 
 enum FenceData {
-    Local(usize),
-    Remote(Vec<u8>),
+    Local(NodeAddrs),
+    Remote(IpAddr, Vec<u8>),
 }
 
 pub struct NetFence<'a, D> {
@@ -64,19 +117,61 @@ pub struct NetFence<'a, D> {
     sequences: HashMap<Participants, Sequence>,
     in_flight: HashMap<FenceId, FenceAcc>,
     discovery: &'a D,
+    completed: watch::Sender<u64>,
+    dump: Option<broadcast::Receiver<()>>,
+    wire: wire::Options,
 }
 
 impl<'a, D: PeerDiscovery> NetFence<'a, D> {
     pub async fn new(addr: SocketAddr, discovery: &'a D) -> Result<Self, ModexError<D::Error>> {
         let listener: net::TcpListener = net::TcpListener::bind(addr).await?;
+        let (completed, _) = watch::channel(0);
         Ok(Self {
             listener,
             discovery,
             sequences: Default::default(),
             in_flight: Default::default(),
+            completed,
+            dump: None,
+            wire: Default::default(),
         })
     }
 
+    /// Authenticate connections to and from peers with `secret`.
+    pub fn with_secret(mut self, secret: wire::Secret) -> Self {
+        self.wire.secret = Some(secret);
+        self
+    }
+
+    /// Encrypt connections to and from peers with `tls`.
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.wire.tls = Some(tls);
+        self
+    }
+
+    /// Retry connecting to peers that aren't listening yet with `backoff`.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.wire.backoff = backoff;
+        self
+    }
+
+    /// Compress fence data of at least `threshold` bytes.
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.wire.compress_above = Some(threshold);
+        self
+    }
+
+    /// Log the state of all in-flight fences whenever `trigger` fires.
+    pub fn with_dump(mut self, trigger: broadcast::Receiver<()>) -> Self {
+        self.dump = Some(trigger);
+        self
+    }
+
+    /// The number of fences completed so far, for progress reporting.
+    pub fn completed(&self) -> watch::Receiver<u64> {
+        self.completed.subscribe()
+    }
+
     pub fn addr(&self) -> SocketAddr {
         #[allow(clippy::unwrap_used, reason = "We know we have a socket bound")]
         self.listener.local_addr().unwrap()
@@ -113,7 +208,7 @@ impl<'a, D: PeerDiscovery> NetFence<'a, D> {
         sys::pmix_proc_t { rank, nspace }
     }
 
-    async fn parse_header(c: &mut net::TcpStream) -> Result<FenceId, io::Error> {
+    async fn parse_header<R: AsyncRead + Unpin>(c: &mut R) -> Result<FenceId, io::Error> {
         let mut buf = [0; mem::size_of::<sys::pmix_rank_t>()];
         c.read_exact(buf.as_mut_slice()).await?;
         let nproc = sys::pmix_rank_t::from_be_bytes(buf);
@@ -134,14 +229,27 @@ impl<'a, D: PeerDiscovery> NetFence<'a, D> {
         peers: Vec<SocketAddr>,
         header: Vec<u8>,
         data: globals::CData,
-    ) -> Result<(), io::Error> {
+        options: wire::Options,
+    ) -> Result<(), wire::Error> {
+        let payload = wire::Payload::new(&data, &options)?;
         stream::iter(peers)
             .map(Ok)
             .try_for_each(async |peer| {
-                let mut s = connect_peer(&peer).await?;
-                s.write_all(&header).await?;
-                s.write_all(&data).await?;
-                Ok(())
+                let span = debug_span!("fence_send", %peer);
+                async {
+                    let mut s = wire::connect(&peer, Endpoint::Fence, &options).await?;
+                    wire::write_frame(&mut s, &header).await?;
+                    s.write_payload(&payload).await?;
+                    s.shutdown().await?;
+                    debug!(
+                        bytes = data.len(),
+                        compressed = payload.compressed_len(),
+                        "sent fence data"
+                    );
+                    Ok(())
+                }
+                .instrument(span)
+                .await
             })
             .await
     }
@@ -157,34 +265,61 @@ impl<'a, D: PeerDiscovery> NetFence<'a, D> {
     fn accept_event(
         &mut self,
         e: globals::FenceEvent,
-    ) -> impl Future<Output = Result<(FenceId, usize), ModexError<D::Error>>> + use<'a, D> {
+    ) -> impl Future<Output = Result<(FenceId, NodeAddrs), ModexError<D::Error>>> + use<'a, D> {
         let globals::FenceEvent { procs, data, cb } = e;
         let id = self.fence_id(procs.clone());
+        let span = debug_span!("fence_event", fence = %id);
+        let _enter = span.enter();
+        debug!("local processes entered fence");
         let acc = self.in_flight.entry(id.clone()).or_default();
         // Record the callback for future status reports. This must happen synchronously.
         let _ = acc.cb.insert(cb);
+        let _ = acc.started.insert(Instant::now());
+        counter!(telemetry::FENCES_STARTED).increment(1);
 
         let discovery = self.discovery;
+        let options = self.wire.clone();
         async move {
             let peers = discovery
                 .peers(&procs, Endpoint::Fence)
                 .await
                 .map_err(ModexError::Peer)?;
             let npeers = peers.len();
+            histogram!(telemetry::FENCE_BYTES, "direction" => "sent")
+                .record((data.len() * npeers) as f64);
             let header = Self::serialize_header(&id);
-            Self::send(peers, header, data).await?;
-            Ok((id, npeers))
+            let nodes = peers
+                .iter()
+                .map(|(node_rank, addr)| (*node_rank, addr.ip()))
+                .collect();
+            Self::send(peers.into_values().collect(), header, data, options).await?;
+            Ok((id, nodes))
         }
+        .instrument(span.clone())
     }
 
-    async fn accept_conn(mut c: net::TcpStream) -> Result<(FenceId, Vec<u8>), io::Error> {
-        let id = Self::parse_header(&mut c).await?;
-        let mut data = Vec::new();
-        c.read_to_end(&mut data).await?;
-        Ok((id, data))
+    #[instrument(
+        level = "debug",
+        name = "fence_conn",
+        skip(c, options),
+        fields(fence = field::Empty)
+    )]
+    async fn accept_conn(
+        c: net::TcpStream,
+        peer: SocketAddr,
+        options: wire::Options,
+    ) -> Result<(FenceId, FenceData), wire::Error> {
+        let mut c = wire::accept(c, Endpoint::Fence, &options).await?;
+        let header = wire::read_frame(&mut c).await?;
+        let id = Self::parse_header(&mut header.as_slice()).await?;
+        Span::current().record("fence", field::display(&id));
+        let data = c.read_payload().await?;
+        debug!(bytes = data.len(), "received fence data");
+        Ok((id, FenceData::Remote(peer.ip(), data)))
     }
 
     fn complete_fence(&mut self, id: FenceId, data: FenceData) {
+        let _span = debug_span!("fence_update", fence = %id).entered();
         let result = match self.in_flight.entry(id) {
             Entry::Occupied(mut e) => {
                 let acc = e.get_mut();
@@ -209,10 +344,38 @@ impl<'a, D: PeerDiscovery> NetFence<'a, D> {
         };
 
         if let Some((cb, data)) = result {
+            debug!(bytes = data.len(), "fence complete");
             cb.call(sys::PMIX_SUCCESS as sys::pmix_status_t, data);
+            self.completed.send_modify(|n| *n += 1);
+        }
+    }
+
+    fn log_in_flight(&self) {
+        info!(count = self.in_flight.len(), "in-flight fences");
+        for (id, acc) in &self.in_flight {
+            let arrived = acc
+                .arrivals
+                .iter()
+                .map(|(peer, _)| *peer)
+                .collect::<HashSet<_>>();
+            let missing = acc
+                .peers
+                .iter()
+                .filter(|(_, peer)| !arrived.contains(peer))
+                .map(|(node_rank, peer)| format!("{} ({})", node_rank, peer))
+                .collect::<Vec<_>>();
+            info!(
+                fence = %id,
+                local = acc.started.is_some(),
+                expected = ?acc.expected,
+                received = acc.complete,
+                ?missing,
+                "in-flight fence"
+            );
         }
     }
 
+    #[instrument(name = "fence", skip_all, fields(addr = %self.addr()))]
     pub async fn serve(
         mut self,
         mut events: mpsc::UnboundedReceiver<globals::FenceEvent>,
@@ -227,18 +390,21 @@ impl<'a, D: PeerDiscovery> NetFence<'a, D> {
                     None => break Ok(()),
                 },
                 c = self.listener.accept().fuse() => match c {
-                    Ok((c, _)) => remote.push(Self::accept_conn(c)),
+                    Ok((c, peer)) => remote.push(Self::accept_conn(c, peer, self.wire.clone())),
                     Err(err) => warn!(%err, "fence accept"),
                 },
                 l = local.select_next_some() => match l {
-                    Ok((id, npeers)) => self.complete_fence(id, FenceData::Local(npeers)),
+                    Ok((id, peers)) => self.complete_fence(id, FenceData::Local(peers)),
                     Err(err) => {
                         warn!(%err, "local fence");
                         break Err(err)
                     }
                 },
+                () = telemetry::dump_requested(&mut self.dump).fuse() => self.log_in_flight(),
                 r = remote.select_next_some() => match r {
-                    Ok((id, data)) => self.complete_fence(id, FenceData::Remote(data)),
+                    Ok((id, data)) => self.complete_fence(id, data),
+                    // Not from a peer, so can't be part of any fence
+                    Err(err) if err.is_rejected() => warn!(%err, "rejected fence connection"),
                     Err(err) => {
                         warn!(%err, "remote fence");
                         break Err(err.into())
@@ -249,6 +415,7 @@ impl<'a, D: PeerDiscovery> NetFence<'a, D> {
 
         events.close(); // Stop accepting more events from the PMIx server
         while let Some(globals::FenceEvent { cb, .. }) = events.recv().await {
+            counter!(telemetry::FENCES_FAILED).increment(1);
             cb.call(sys::PMIX_ERROR, Vec::new());
         }
 
@@ -257,6 +424,7 @@ impl<'a, D: PeerDiscovery> NetFence<'a, D> {
             .drain()
             .flat_map(|(_, FenceAcc { cb, .. })| cb)
         {
+            counter!(telemetry::FENCES_FAILED).increment(1);
             cb.call(sys::PMIX_ERROR, Vec::new());
         }
 
@@ -388,6 +556,46 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn test_fence_rejects_strangers() {
+        let tmpdir = TempDir::new("fence-test").unwrap();
+        let discovery = DirectoryPeers::new(tmpdir.path(), 1, 2);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let fence = NetFence::new(addr, &discovery).await.unwrap();
+        discovery.register(&fence.addr()).unwrap();
+        let mut stranger = net::TcpStream::connect(fence.addr()).await.unwrap();
+        stranger
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (other, other_tx) = create_fence(&discovery).await;
+
+        let procs = vec![sys::pmix_proc_t {
+            nspace: [0; _],
+            rank: sys::PMIX_RANK_WILDCARD,
+        }];
+        let results = [tx, other_tx].into_iter().enumerate().map(|(i, tx)| {
+            let data = globals::CData::from_slice(&[i as u8]).unwrap();
+            let (event, rx) = create_event(procs.clone(), data);
+            tx.send(event).unwrap();
+            rx
+        });
+
+        let fences = join(pin!(fence.serve(rx)), pin!(other));
+        let Either::Left((results, _)) = select(join_all(results), fences).await else {
+            panic!("expected response");
+        };
+        for result in results {
+            let (status, data) = result.unwrap();
+            assert_eq!(status, sys::PMIX_SUCCESS as sys::pmix_status_t);
+            assert_eq!(
+                data.into_iter().collect::<HashSet<_>>(),
+                HashSet::from([0, 1])
+            );
+        }
+    }
+
     #[tokio::test]
     async fn test_fence_cycle() {
         let nnodes = 3;
@@ -482,10 +690,10 @@ mod test {
                     .peers(&procs, Endpoint::Fence)
                     .await
                     .map_err(ModexError::Peer)?;
-                stream::iter(peers)
+                stream::iter(peers.into_values())
                     .map(Ok)
                     .try_for_each(async |peer| {
-                        let s = connect_peer(&peer).await?;
+                        let s = wire::connect(&peer, Endpoint::Fence, &Default::default()).await?;
                         drop(s); // Drop without writing any data to trigger an error
                         Ok(())
                     })
@@ -531,4 +739,24 @@ mod test {
         assert_eq!(status, sys::PMIX_ERROR);
         assert!(exit.is_err());
     }
+
+    #[test]
+    fn test_fence_id_display() {
+        let mut nspace = [0; _];
+        nspace[..3].copy_from_slice(u8_to_char(b"foo"));
+        let procs = (0..6)
+            .map(|rank| sys::pmix_proc_t { nspace, rank })
+            .collect::<Participants>();
+        assert_eq!(
+            FenceId(procs, 2).to_string(),
+            "[foo:0,foo:1,foo:2,foo:3,... (6 total)]#2"
+        );
+
+        let procs = [sys::pmix_proc_t {
+            nspace,
+            rank: sys::PMIX_RANK_WILDCARD,
+        }];
+        let id = FenceId(procs.into_iter().collect(), 0);
+        assert_eq!(id.to_string(), "[foo:*]#0");
+    }
 }
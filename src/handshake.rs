@@ -0,0 +1,409 @@
+//! A check at startup that every pod of the job was started with a consistent
+//! configuration, which would otherwise hang in the first fence.
+//!
+//! Every pod sends a fingerprint of its configuration to node 0, which compares
+//! them with its own. Once all pods have checked in, node 0 replies to each with
+//! the differences it found (if any), so that every pod fails with the same
+//! error. Pods that don't check in within the discovery timeout are reported
+//! as differences too.
+
+use std::{collections::BTreeMap, io, net::SocketAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{net, time};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    ModexError,
+    net::Backoff,
+    peer::{Endpoint, PeerDiscovery},
+    pmix::{nspace_from_str, sys},
+    tls::Tls,
+    wire,
+};
+
+/// The settings every pod of the job must agree on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub version: String,
+    pub namespace: String,
+    pub nproc: u16,
+    pub nnodes: u32,
+    pub universe_size: u32,
+}
+
+impl Fingerprint {
+    /// The fingerprint of this build of pmi-k8s, with the given settings.
+    pub fn new(namespace: String, nproc: u16, nnodes: u32, universe_size: u32) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            namespace,
+            nproc,
+            nnodes,
+            universe_size,
+        }
+    }
+
+    /// Describe how `theirs` (from `node_rank`) differs from ours.
+    fn differences(&self, node_rank: u32, theirs: &Self) -> Vec<String> {
+        let mut differences = Vec::new();
+        let mut compare = |name: &str, ours: String, theirs: String| {
+            if ours != theirs {
+                differences.push(format!(
+                    "node {} has {} {}, but node 0 has {}",
+                    node_rank, name, theirs, ours
+                ));
+            }
+        };
+        compare("version", self.version.clone(), theirs.version.clone());
+        compare(
+            "namespace",
+            self.namespace.clone(),
+            theirs.namespace.clone(),
+        );
+        compare("nproc", self.nproc.to_string(), theirs.nproc.to_string());
+        compare("nnodes", self.nnodes.to_string(), theirs.nnodes.to_string());
+        compare(
+            "universe size",
+            self.universe_size.to_string(),
+            theirs.universe_size.to_string(),
+        );
+        differences
+    }
+}
+
+/// The message each pod sends to node 0.
+#[derive(Serialize, Deserialize, Debug)]
+struct Report {
+    node_rank: u32,
+    fingerprint: Fingerprint,
+}
+
+pub struct Handshake<'a, D> {
+    /// Only node 0 listens for reports.
+    listener: Option<net::TcpListener>,
+    discovery: &'a D,
+    node_rank: u32,
+    fingerprint: Fingerprint,
+    timeout: Option<Duration>,
+    wire: wire::Options,
+}
+
+impl<'a, D: PeerDiscovery> Handshake<'a, D> {
+    pub async fn new(
+        addr: SocketAddr,
+        discovery: &'a D,
+        node_rank: u32,
+        fingerprint: Fingerprint,
+    ) -> Result<Self, ModexError<D::Error>> {
+        let listener = if node_rank == 0 && fingerprint.nnodes > 1 {
+            Some(net::TcpListener::bind(addr).await?)
+        } else {
+            None
+        };
+        Ok(Self {
+            listener,
+            discovery,
+            node_rank,
+            fingerprint,
+            timeout: None,
+            wire: Default::default(),
+        })
+    }
+
+    /// Give up on pods that haven't checked in within `timeout`. Node 0 then
+    /// reports them to the others, which wait twice as long for its verdict.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Authenticate connections to and from peers with `secret`.
+    pub fn with_secret(mut self, secret: wire::Secret) -> Self {
+        self.wire.secret = Some(secret);
+        self
+    }
+
+    /// Encrypt connections to and from peers with `tls`.
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.wire.tls = Some(tls);
+        self
+    }
+
+    /// Retry connecting to peers that aren't listening yet with `backoff`.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.wire.backoff = backoff;
+        self
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        #[allow(clippy::unwrap_used, reason = "We know we have a socket bound")]
+        self.listener.as_ref().map(|l| l.local_addr().unwrap())
+    }
+
+    /// Check that every pod agrees with node 0, failing with the differences
+    /// if not.
+    #[instrument(name = "handshake", skip_all, fields(node_rank = self.node_rank))]
+    pub async fn run(self) -> Result<(), ModexError<D::Error>> {
+        if self.fingerprint.nnodes <= 1 {
+            return Ok(());
+        }
+        let differences = match &self.listener {
+            Some(listener) => self.collect(listener).await?,
+            None => self.report().await?,
+        };
+        if differences.is_empty() {
+            info!("all pods are configured consistently");
+            Ok(())
+        } else {
+            Err(ModexError::Inconsistent(differences))
+        }
+    }
+
+    /// Send our fingerprint to node 0, and wait for its verdict.
+    async fn report(&self) -> Result<Vec<String>, ModexError<D::Error>> {
+        let Some(timeout) = self.timeout else {
+            return self.exchange().await;
+        };
+        time::timeout(timeout * 2, self.exchange())
+            .await
+            .unwrap_or_else(|_| {
+                let msg = "timed out waiting for the handshake verdict from node 0";
+                Err(io::Error::new(io::ErrorKind::TimedOut, msg).into())
+            })
+    }
+
+    async fn exchange(&self) -> Result<Vec<String>, ModexError<D::Error>> {
+        let first = sys::pmix_proc_t {
+            nspace: nspace_from_str(&self.fingerprint.namespace),
+            rank: 0,
+        };
+        let peer = self
+            .discovery
+            .peer(&first, Endpoint::Handshake)
+            .await
+            .map_err(ModexError::Peer)?;
+
+        let mut s = wire::connect(&peer, Endpoint::Handshake, &self.wire).await?;
+        let report = Report {
+            node_rank: self.node_rank,
+            fingerprint: self.fingerprint.clone(),
+        };
+        wire::write_frame(
+            &mut s,
+            &serde_json::to_vec(&report).map_err(io::Error::from)?,
+        )
+        .await?;
+        let verdict = wire::read_frame(&mut s).await?;
+        debug!(%peer, "received handshake verdict");
+        Ok(serde_json::from_slice(&verdict).map_err(io::Error::from)?)
+    }
+
+    /// Wait for every other pod's fingerprint, then reply to all with the
+    /// differences from ours.
+    async fn collect(
+        &self,
+        listener: &net::TcpListener,
+    ) -> Result<Vec<String>, ModexError<D::Error>> {
+        let mut reports = BTreeMap::new();
+        let mut differences = Vec::new();
+        let accept = self.accept(listener, &mut reports, &mut differences);
+        match self.timeout {
+            Some(timeout) => match time::timeout(timeout, accept).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    let missing = (1..self.fingerprint.nnodes)
+                        .filter(|node_rank| !reports.contains_key(node_rank))
+                        .map(|node_rank| node_rank.to_string())
+                        .collect::<Vec<_>>();
+                    let nodes = if missing.len() == 1 { "node" } else { "nodes" };
+                    differences.push(format!(
+                        "{} {} did not check in within {:?}",
+                        nodes,
+                        missing.join(", "),
+                        timeout
+                    ));
+                }
+            },
+            None => accept.await?,
+        }
+
+        let verdict = serde_json::to_vec(&differences).map_err(io::Error::from)?;
+        for s in reports.values_mut().flatten() {
+            if let Err(err) = wire::write_frame(s, &verdict).await {
+                warn!(%err, "unable to send handshake verdict");
+            }
+        }
+        Ok(differences)
+    }
+
+    /// Accept reports until every other pod has checked in, recording how
+    /// they differ from ours.
+    async fn accept(
+        &self,
+        listener: &net::TcpListener,
+        reports: &mut BTreeMap<u32, Vec<wire::Stream<net::TcpStream>>>,
+        differences: &mut Vec<String>,
+    ) -> io::Result<()> {
+        while (1..self.fingerprint.nnodes).any(|node_rank| !reports.contains_key(&node_rank)) {
+            let (c, peer) = listener.accept().await?;
+            let (s, report) = match self.receive(c).await {
+                Ok(received) => received,
+                Err(err) if err.is_rejected() => {
+                    warn!(%err, %peer, "rejected handshake connection");
+                    continue;
+                }
+                // Like rejected connections, reports that can't be read don't
+                // count as any pod checking in
+                Err(err) => {
+                    warn!(%err, %peer, "invalid handshake report");
+                    continue;
+                }
+            };
+            let Report {
+                node_rank,
+                fingerprint,
+            } = report;
+            debug!(%peer, node_rank, "received handshake report");
+            if node_rank == 0 || node_rank >= self.fingerprint.nnodes {
+                differences.push(format!(
+                    "{} claims to be node {}, but node 0 has nnodes {}",
+                    peer.ip(),
+                    node_rank,
+                    self.fingerprint.nnodes
+                ));
+            } else if reports.contains_key(&node_rank) {
+                differences.push(format!("node {} was started twice", node_rank));
+            }
+            differences.extend(self.fingerprint.differences(node_rank, &fingerprint));
+            reports.entry(node_rank).or_insert_with(Vec::new).push(s);
+        }
+        Ok(())
+    }
+
+    /// Accept a connection from a pod, and read its report.
+    async fn receive(
+        &self,
+        c: net::TcpStream,
+    ) -> Result<(wire::Stream<net::TcpStream>, Report), wire::Error> {
+        let mut s = wire::accept(c, Endpoint::Handshake, &self.wire).await?;
+        let report = wire::read_frame(&mut s).await?;
+        let report = serde_json::from_slice(&report).map_err(io::Error::from)?;
+        Ok((s, report))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::panic)]
+    use std::net::Ipv4Addr;
+
+    use futures::future::join_all;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::peer::DirectoryPeers;
+
+    async fn handshake(fingerprints: Vec<Fingerprint>) -> Vec<Result<(), Vec<String>>> {
+        let tmpdir = TempDir::new("handshake-test").unwrap();
+        let nnodes = fingerprints.len() as u32;
+        let discovery = DirectoryPeers::new(tmpdir.path(), 1, nnodes);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let mut handshakes = Vec::new();
+        for (node_rank, fingerprint) in fingerprints.into_iter().enumerate() {
+            let handshake = Handshake::new(addr, &discovery, node_rank as u32, fingerprint)
+                .await
+                .unwrap();
+            if let Some(addr) = handshake.addr() {
+                discovery.register(&addr).unwrap();
+            }
+            handshakes.push(handshake.run());
+        }
+        join_all(handshakes)
+            .await
+            .into_iter()
+            .map(|result| {
+                result.map_err(|err| match err {
+                    ModexError::Inconsistent(differences) => differences,
+                    err => vec![err.to_string()],
+                })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let fingerprint = Fingerprint::new("job".to_owned(), 2, 3, 6);
+        let results = handshake(vec![fingerprint.clone(); 3]).await;
+        assert!(results.iter().all(Result::is_ok));
+
+        let mut mismatched = fingerprint.clone();
+        mismatched.nproc = 4;
+        let results = handshake(vec![fingerprint.clone(), fingerprint, mismatched]).await;
+        let expected = vec!["node 2 has nproc 4, but node 0 has 2".to_owned()];
+        for result in results {
+            assert_eq!(result, Err(expected.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_report() {
+        let tmpdir = TempDir::new("handshake-test").unwrap();
+        let discovery = DirectoryPeers::new(tmpdir.path(), 1, 2);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let fingerprint = Fingerprint::new("job".to_owned(), 2, 2, 4);
+        let first = Handshake::new(addr, &discovery, 0, fingerprint.clone())
+            .await
+            .unwrap();
+        let addr = first.addr().unwrap();
+        let second = Handshake::new(addr, &discovery, 1, fingerprint)
+            .await
+            .unwrap();
+        discovery.register(&addr).unwrap();
+
+        // Node 0 handles connections in order, so the invalid one comes first
+        let invalid_then_second = async {
+            let mut s = wire::connect(&addr, Endpoint::Handshake, &Default::default())
+                .await
+                .unwrap();
+            wire::write_frame(&mut s, b"not json").await.unwrap();
+            second.run().await
+        };
+        let (first, second) = tokio::join!(first.run(), invalid_then_second);
+        assert!(first.is_ok(), "{:?}", first);
+        assert!(second.is_ok(), "{:?}", second);
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let tmpdir = TempDir::new("handshake-test").unwrap();
+        let discovery = DirectoryPeers::new(tmpdir.path(), 1, 3);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let fingerprint = Fingerprint::new("job".to_owned(), 2, 3, 6);
+        let first = Handshake::new(addr, &discovery, 0, fingerprint.clone())
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(100));
+        discovery.register(&first.addr().unwrap()).unwrap();
+        let second = Handshake::new(addr, &discovery, 1, fingerprint)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(100));
+
+        // Node 2 never checks in
+        let (first, second) = tokio::join!(first.run(), second.run());
+        let expected = vec!["node 2 did not check in within 100ms".to_owned()];
+        for result in [first, second] {
+            match result {
+                Err(ModexError::Inconsistent(differences)) => assert_eq!(differences, expected),
+                result => panic!("expected inconsistency, got {:?}", result),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_node() {
+        let fingerprint = Fingerprint::new("job".to_owned(), 2, 1, 2);
+        assert_eq!(handshake(vec![fingerprint]).await, [Ok(())]);
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net, time,
+};
+use tracing::warn;
+
+/// Conditions reported by the health endpoints.
+#[derive(Clone, Copy, Debug)]
+pub enum Check {
+    /// The fence and modex listeners are bound.
+    Bound,
+    /// The PMIx server is initialized, and the namespace registered.
+    Initialized,
+    /// Environment files are written (or not needed).
+    EnvWritten,
+    /// All peers in the job have been discovered.
+    Discovered,
+}
+
+/// Liveness and readiness of this pod, served over HTTP as `/healthz` and
+/// `/readyz` for use as Kubernetes probes.
+#[derive(Default)]
+pub struct Health {
+    bound: AtomicBool,
+    initialized: AtomicBool,
+    env_written: AtomicBool,
+    discovered: AtomicBool,
+}
+
+// Requests are only ever a request line and a few headers
+const MAX_REQUEST: u64 = 8192;
+// Probes time out after a second by default, so there's no point in waiting
+// much longer for a slow client
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Health {
+    pub fn set(&self, check: Check) {
+        self.flag(check).store(true, Ordering::Relaxed);
+    }
+
+    fn flag(&self, check: Check) -> &AtomicBool {
+        match check {
+            Check::Bound => &self.bound,
+            Check::Initialized => &self.initialized,
+            Check::EnvWritten => &self.env_written,
+            Check::Discovered => &self.discovered,
+        }
+    }
+
+    fn report(&self, checks: &[(&str, Check)]) -> (bool, String) {
+        let mut ok = true;
+        let mut body = String::new();
+        for (name, check) in checks {
+            let passed = self.flag(*check).load(Ordering::Relaxed);
+            ok &= passed;
+            let status = if passed { "ok" } else { "waiting" };
+            body.push_str(&format!("{}: {}\n", name, status));
+        }
+        (ok, body)
+    }
+
+    fn respond(&self, path: &str) -> (&'static str, String) {
+        let report = match path {
+            // Only reports that the process is alive, as it may take a while
+            // to get to anything else (e.g. waiting for other pods)
+            "/healthz" => (true, "ok\n".to_owned()),
+            "/readyz" => self.report(&[
+                ("listeners", Check::Bound),
+                ("server", Check::Initialized),
+                ("env", Check::EnvWritten),
+                ("peers", Check::Discovered),
+            ]),
+            _ => return ("404 Not Found", "not found\n".to_owned()),
+        };
+        match report {
+            (true, body) => ("200 OK", body),
+            (false, body) => ("503 Service Unavailable", body),
+        }
+    }
+
+    async fn handle(&self, mut c: net::TcpStream) -> Result<(), io::Error> {
+        let mut reader = BufReader::new((&mut c).take(MAX_REQUEST));
+        let mut request = String::new();
+        reader.read_line(&mut request).await?;
+        // Read the headers, so the connection is not reset on close
+        let mut line = String::new();
+        while reader.read_line(&mut line).await? > 2 {
+            request.push_str(&line);
+            line.clear();
+        }
+
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+        let (status, body) = self.respond(path);
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        c.write_all(response.as_bytes()).await?;
+        c.shutdown().await
+    }
+
+    /// Serve health checks on `listener` forever.
+    pub async fn serve(self: Arc<Self>, listener: net::TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((c, _)) => {
+                    let health = self.clone();
+                    tokio::spawn(async move {
+                        match time::timeout(REQUEST_TIMEOUT, health.handle(c)).await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(err)) => warn!(%err, "health check"),
+                            Err(_) => warn!("health check timed out"),
+                        }
+                    });
+                }
+                Err(err) => warn!(%err, "health accept"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut c = net::TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        c.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        c.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let listener = net::TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let health = Arc::new(Health::default());
+        tokio::spawn(health.clone().serve(listener));
+
+        assert!(get(addr, "/healthz").await.starts_with("HTTP/1.1 200"));
+
+        let response = get(addr, "/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.ends_with("peers: waiting\n"));
+        health.set(Check::Bound);
+        health.set(Check::Initialized);
+        health.set(Check::EnvWritten);
+        health.set(Check::Discovered);
+        assert!(get(addr, "/readyz").await.starts_with("HTTP/1.1 200"));
+
+        assert!(get(addr, "/foo").await.starts_with("HTTP/1.1 404"));
+    }
+}
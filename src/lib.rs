@@ -1,12 +1,26 @@
-use std::{error::Error, fmt, io, path::PathBuf};
+use std::{error::Error, fmt, io, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
+pub mod config;
+pub mod credential;
+pub mod envfile;
+pub mod exec;
 pub mod fence;
+pub mod handshake;
+pub mod health;
 pub mod modex;
 pub mod net;
 pub mod peer;
 pub mod pmix;
+pub mod query;
+pub mod resources;
+pub mod telemetry;
+pub mod template;
+pub mod tls;
+pub mod wire;
+
+pub use envfile::EnvFormat;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ModexError<E: Error + fmt::Debug> {
@@ -16,52 +30,697 @@ pub enum ModexError<E: Error + fmt::Debug> {
     Server(#[from] pmix::PmixError),
     #[error("error in peer discovery")]
     Peer(E),
+    #[error("error in peer protocol")]
+    Protocol(#[from] wire::Error),
+    #[error("pods of the job are configured inconsistently: {}", .0.join("; "))]
+    Inconsistent(Vec<String>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("app context is missing a command")]
+    MissingCommand,
+    #[error("{0} is missing its value")]
+    MissingValue(String),
+    #[error("invalid process count for app context: {0:?}")]
+    InvalidCount(String),
+    #[error("invalid environment variable for app context (expected KEY=VALUE): {0:?}")]
+    InvalidEnv(String),
+    #[error("only one app context may omit its process count")]
+    AmbiguousCount,
+    #[error("app contexts request {requested} processes, but the job has {available}")]
+    SizeMismatch { requested: u32, available: u32 },
+    #[error("app contexts request more processes than a job can have")]
+    TooManyProcesses,
+}
+
+/// Options that may start an app context. A command line only uses the MPMD
+/// syntax if it starts with one, so `:` is otherwise an ordinary argument.
+const APP_OPTIONS: [&str; 4] = ["-n", "--np", "-x", "--env"];
+
+fn checked_sum(counts: impl IntoIterator<Item = u32>) -> Result<u32, AppError> {
+    counts
+        .into_iter()
+        .try_fold(0u32, |sum, n| sum.checked_add(n))
+        .ok_or(AppError::TooManyProcesses)
+}
+
+/// One program to launch as part of an MPMD job, with its share of the ranks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppContext {
+    pub nproc: u32,
+    pub command: String,
+    pub args: Vec<String>,
+    pub envs: Vec<(String, String)>,
+}
+
+impl AppContext {
+    /// Find the app (and its index) that `rank` belongs to. Apps are assigned
+    /// contiguous blocks of ranks, in the order they were given.
+    pub fn for_rank(apps: &[Self], rank: u32) -> Option<(u32, &Self)> {
+        let mut start = 0;
+        for (appnum, app) in apps.iter().enumerate() {
+            if rank < start + app.nproc {
+                return Some((appnum as u32, app));
+            }
+            start += app.nproc;
+        }
+        None
+    }
+
+    fn parse(argv: &[String]) -> Result<(Option<u32>, Self), AppError> {
+        let mut nproc = None;
+        let mut envs = Vec::new();
+        let mut argv = argv.iter();
+        let command = loop {
+            match argv.next().map(String::as_str) {
+                Some(option @ ("-n" | "--np")) => {
+                    let missing = || AppError::MissingValue(option.to_owned());
+                    let n = argv.next().ok_or_else(missing)?;
+                    let n = n.parse().map_err(|_| AppError::InvalidCount(n.clone()))?;
+                    nproc = Some(n);
+                }
+                Some(option @ ("-x" | "--env")) => {
+                    let missing = || AppError::MissingValue(option.to_owned());
+                    let env = argv.next().ok_or_else(missing)?;
+                    let (k, v) = env
+                        .split_once('=')
+                        .ok_or_else(|| AppError::InvalidEnv(env.clone()))?;
+                    envs.push((k.to_owned(), v.to_owned()));
+                }
+                Some(command) => break command.to_owned(),
+                None => return Err(AppError::MissingCommand),
+            }
+        };
+        let args = argv.cloned().collect();
+        let app = Self {
+            nproc: 0,
+            command,
+            args,
+            envs,
+        };
+        Ok((nproc, app))
+    }
+}
+
+/// The number of processes to run in each pod.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Nproc {
+    /// Detected from the pod's resources, see [`resources::detect`].
+    Auto,
+    Count(u16),
+}
+
+impl FromStr for Nproc {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err(format!("expected a positive number or `auto`, got {:?}", s)),
+                Ok(n) => Ok(Self::Count(n)),
+            },
+        }
+    }
+}
+
+/// The kind of workload whose pods form the MPI world.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TopologyKind {
+    /// An indexed Job (requires `JOB_NAME` and `JOB_COMPLETION_INDEX`)
+    #[default]
+    Job,
+    /// All replicated jobs of a JobSet (requires `POD_NAME`)
+    #[value(name = "jobset")]
+    JobSet,
+    /// Pods of a StatefulSet, ranked by ordinal (requires `POD_NAME`)
+    #[value(name = "statefulset")]
+    StatefulSet,
+    /// Pods matching `--selector`, ranked by `--index-label` (requires `POD_NAME`)
+    Selector,
+}
+
+/// How log lines are formatted.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Helpers run instead of the PMIx server.
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Wait for a `pmi-k8s` sidecar to write environment files to `--env-dir`,
+    /// then run a command once for each of them.
+    Exec(exec::Args),
 }
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub subcommand: Option<Commands>,
+    /// TOML or YAML file of options, keyed by their long names, e.g.
+    /// `nproc = 4`. Environment variables (`PMI_K8S_NPROC=4`) take precedence
+    /// over the file, and flags over both. Defaults to `PMI_K8S_CONFIG`.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Print the effective options, and where each was set, then exit.
     #[arg(long)]
-    pub nproc: u16,
+    pub print_config: bool,
+    /// Number of processes to run in this pod, which must be the same for
+    /// every pod. `auto` detects it from `--nproc-file`, the GPUs in
+    /// `NVIDIA_VISIBLE_DEVICES` or `CUDA_VISIBLE_DEVICES`, or the container's
+    /// CPU limit or cpuset.
+    #[arg(long, required_unless_present = "print_config")]
+    pub nproc: Option<Nproc>,
+    /// File holding the number of processes for `--nproc=auto`, e.g. a
+    /// downward API volume exposing `limits.cpu`.
+    #[arg(long)]
+    pub nproc_file: Option<PathBuf>,
     #[arg(long)]
     pub env_dir: Option<PathBuf>,
+    /// Running as a native sidecar (an init container with `restartPolicy:
+    /// Always`). Kubernetes restarts native sidecars that exit, so once every
+    /// local rank has finalized, wait to be stopped instead of exiting.
+    #[arg(long, conflicts_with = "command")]
+    pub native_sidecar: bool,
+    /// Format of the files written to `--env-dir`.
+    #[arg(long, value_enum, default_value_t, requires = "env_dir")]
+    pub env_format: EnvFormat,
+    /// Set an environment variable for each process, overriding any set by
+    /// PMIx. The template may refer to `{rank}`, `{local_rank}`, `{node_rank}`
+    /// and `{namespace}`, e.g. `CUDA_VISIBLE_DEVICES={local_rank}`.
+    #[arg(long = "env", value_name = "KEY=TEMPLATE")]
+    pub env: Vec<template::EnvTemplate>,
+    /// As `--env`, but only if the variable is not otherwise set (by PMIx, the
+    /// environment, or `-x`).
+    #[arg(long, value_name = "KEY=TEMPLATE")]
+    pub env_default: Vec<template::EnvTemplate>,
+    /// File of `KEY=TEMPLATE` (as `--env`) and `KEY?=TEMPLATE` (as
+    /// `--env-default`) lines. Flags take precedence over the file.
+    #[arg(long)]
+    pub env_template_file: Option<PathBuf>,
+    /// PMIx namespace to register the job under. Defaults to one derived from
+    /// the Kubernetes Job name and UID. Required with `--topology=selector`,
+    /// where it also names the job's Kubernetes resources.
+    #[arg(long, required_if_eq("topology", "selector"))]
+    pub namespace: Option<String>,
+    #[arg(long, value_enum, default_value_t)]
+    pub topology: TopologyKind,
+    /// First of the five consecutive ports used to connect to peers. Every pod
+    /// of the job must use the same ports.
+    #[arg(long, default_value_t = peer::k8s::PORT, value_parser = clap::value_parser!(u16).range(1..=65531))]
+    pub port: u16,
+    /// Directory to create the PMIx server's socket in. Defaults to the system
+    /// temporary directory.
+    #[arg(long)]
+    pub tmpdir: Option<PathBuf>,
+    /// Write the PMIx server's URI to this file, for tools such as debuggers
+    /// to connect to with `PMIX_SERVER_URI=file:<path>`.
+    #[arg(long, value_name = "FILE")]
+    pub tool_rendezvous: Option<PathBuf>,
+    /// Label selector for the pods of the job, with `--topology=selector`.
+    #[arg(long, required_if_eq("topology", "selector"))]
+    pub selector: Option<String>,
+    /// Label holding each pod's node rank, with `--topology=selector`.
+    #[arg(long, required_if_eq("topology", "selector"))]
+    pub index_label: Option<String>,
+    /// Number of pods in the job, with `--topology=selector`.
+    #[arg(long, required_if_eq("topology", "selector"))]
+    pub nnodes: Option<u32>,
+    /// Fail if the pods of the job don't all have addresses within this many
+    /// seconds, explaining why each missing pod hasn't started (e.g. it is
+    /// unschedulable, or its image can't be pulled).
+    #[arg(long, value_name = "SECONDS")]
+    pub discovery_timeout: Option<u64>,
+    /// Milliseconds to wait before retrying a connection to a peer that isn't
+    /// listening yet. Doubles after each attempt, up to
+    /// `--connect-backoff-max`.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 250)]
+    pub connect_backoff: u64,
+    /// The longest wait between connection attempts, in milliseconds.
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 4000)]
+    pub connect_backoff_max: u64,
+    /// Run as an elastic job: the first `--min-nodes` pods form the MPI world,
+    /// and pods added by resizing the Job are started as new namespaces.
+    #[arg(long, requires = "max_nodes")]
+    pub min_nodes: Option<u32>,
+    /// The largest size (in pods) an elastic job may grow to.
+    #[arg(long, requires = "min_nodes")]
+    pub max_nodes: Option<u32>,
+    /// Report progress and failures as Kubernetes Events on the workload.
+    #[arg(long)]
+    pub events: bool,
+    /// Annotate this pod with its current phase. Requires `POD_NAME`.
+    #[arg(long, requires = "events")]
+    pub annotate_phase: bool,
+    /// Serve Prometheus metrics on this address, e.g. `0.0.0.0:9090`.
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// File holding a secret shared by all pods of the job, used to
+    /// authenticate peers. Defaults to the `PMI_K8S_SECRET` environment
+    /// variable, if set.
+    #[arg(long)]
+    pub secret_file: Option<PathBuf>,
+    /// Authenticate peers with a secret generated for the job and shared
+    /// between its pods through a Kubernetes Secret.
+    #[arg(long, conflicts_with = "secret_file")]
+    pub secret_generate: bool,
+    /// Encrypt peer connections with TLS, using `tls.crt`, `tls.key` and
+    /// `ca.crt` from this directory (e.g. a mounted Secret).
+    #[arg(long)]
+    pub tls_dir: Option<PathBuf>,
+    /// Encrypt peer connections with TLS, using a CA generated for the job and
+    /// shared between its pods through a Kubernetes Secret.
+    #[arg(long, conflicts_with = "tls_dir")]
+    pub tls_generate: bool,
+    /// Compress fence data and direct modex responses of at least this many
+    /// bytes with zstd, when sending to peers that support it.
+    #[arg(long, value_name = "BYTES")]
+    pub compress_above: Option<usize>,
+    /// Serve `/healthz` and `/readyz` on this address, e.g. `0.0.0.0:8080`.
+    #[arg(long)]
+    pub health_addr: Option<SocketAddr>,
+    #[arg(long, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+    /// Log filter, e.g. `debug` or `pmi_k8s::fence=trace`. Overrides
+    /// `RUST_LOG`, which defaults to `info`.
+    #[arg(long)]
+    pub log_level: Option<String>,
     #[arg()]
     pub command: Option<String>,
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     pub args: Vec<String>,
 }
 
+impl Cli {
+    pub fn topology_source(&self) -> peer::topology::Source {
+        use peer::topology::Source;
+
+        match self.topology {
+            TopologyKind::Job => Source::Job,
+            TopologyKind::JobSet => Source::JobSet,
+            TopologyKind::StatefulSet => Source::StatefulSet,
+            TopologyKind::Selector => Source::Selector {
+                name: self.namespace.clone().expect("required by clap"),
+                selector: self.selector.clone().expect("required by clap"),
+                index_label: self.index_label.clone().expect("required by clap"),
+                nnodes: self.nnodes.expect("required by clap"),
+            },
+        }
+    }
+
+    /// How to retry connecting to peers that aren't listening yet.
+    pub fn backoff(&self) -> net::Backoff {
+        net::Backoff {
+            initial: Duration::from_millis(self.connect_backoff),
+            max: Duration::from_millis(self.connect_backoff_max),
+        }
+    }
+
+    /// The secret used to authenticate peers, if any.
+    pub fn secret(&self) -> Result<Option<wire::Secret>, io::Error> {
+        let secret = match &self.secret_file {
+            Some(path) => {
+                // Allow files written by `echo`, or editors that add a newline
+                let mut secret = std::fs::read(path)?;
+                if secret.last() == Some(&b'\n') {
+                    secret.pop();
+                }
+                secret
+            }
+            None => match std::env::var_os("PMI_K8S_SECRET") {
+                Some(secret) => secret.into_encoded_bytes(),
+                None => return Ok(None),
+            },
+        };
+        if secret.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shared secret is empty",
+            ));
+        }
+        Ok(Some(wire::Secret::new(secret)))
+    }
+
+    /// Templates for the environment of each process, from the command line
+    /// and `--env-template-file`.
+    pub fn env_templates(&self) -> Result<template::EnvTemplates, template::Error> {
+        let mut templates = match &self.env_template_file {
+            Some(path) => template::EnvTemplates::load(path)?,
+            None => template::EnvTemplates::default(),
+        };
+        templates.extend(template::EnvTemplates {
+            overrides: self.env.clone(),
+            defaults: self.env_default.clone(),
+        });
+        Ok(templates)
+    }
+
+    /// Split the command line into app contexts, separated by `:` as for
+    /// `mpirun -n 1 a : -n 7 b`. Each context may start with `-n N` (its number
+    /// of processes in the whole job) and `-x KEY=VALUE` (extra environment).
+    /// At most one context may omit `-n`, and receives the remaining ranks.
+    /// Unless the command line starts with `-n` or `-x`, it is a single program.
+    ///
+    /// Returns no app contexts if no command was given (sidecar mode).
+    pub fn apps(&self, job_size: u32) -> Result<Vec<AppContext>, AppError> {
+        let Some(command) = &self.command else {
+            return Ok(Vec::new());
+        };
+        let argv = std::iter::once(command.clone())
+            .chain(self.args.iter().cloned())
+            .collect::<Vec<_>>();
+
+        let mut apps = if APP_OPTIONS.contains(&command.as_str()) {
+            argv.split(|arg| arg == ":")
+                .map(AppContext::parse)
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            vec![AppContext::parse(&argv)?]
+        };
+
+        let requested = checked_sum(apps.iter().filter_map(|(n, _)| *n))?;
+        let unsized_apps = apps.iter().filter(|(n, _)| n.is_none()).count();
+        let remainder = match unsized_apps {
+            0 => 0,
+            1 => job_size.saturating_sub(requested),
+            _ => return Err(AppError::AmbiguousCount),
+        };
+        for (n, app) in apps.iter_mut() {
+            app.nproc = n.unwrap_or(remainder);
+        }
+
+        let requested = checked_sum(apps.iter().map(|(_, app)| app.nproc))?;
+        if requested != job_size || apps.iter().any(|(_, app)| app.nproc == 0) {
+            return Err(AppError::SizeMismatch {
+                requested,
+                available: job_size,
+            });
+        }
+        Ok(apps.into_iter().map(|(_, app)| app).collect())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    #![allow(clippy::unwrap_used)]
+    #![allow(clippy::unwrap_used, clippy::panic)]
     use super::*;
 
     #[test]
     fn test_args() {
         let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "foo"]).unwrap();
-        assert_eq!(cli.nproc, 2);
+        assert_eq!(cli.nproc, Some(Nproc::Count(2)));
         assert_eq!(cli.command, "foo".to_owned().into());
         assert!(cli.args.is_empty());
 
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=auto", "foo"]).unwrap();
+        assert_eq!(cli.nproc, Some(Nproc::Auto));
+        assert!(Cli::try_parse_from(["pmi-k8s", "--nproc=0", "foo"]).is_err());
+
         let cli =
             Cli::try_parse_from(["pmi-k8s", "--nproc=2", "foo", "--", "bar", "--baz"]).unwrap();
-        assert_eq!(cli.nproc, 2);
+        assert_eq!(cli.nproc, Some(Nproc::Count(2)));
         assert_eq!(cli.command, "foo".to_owned().into());
         assert_eq!(cli.args, ["bar", "--baz"]);
 
         let cli =
             Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "foo", "bar", "--baz"]).unwrap();
-        assert_eq!(cli.nproc, 2);
+        assert_eq!(cli.nproc, Some(Nproc::Count(2)));
         assert_eq!(cli.command, "foo".to_owned().into());
         assert_eq!(cli.args, ["bar", "--baz"]);
 
         let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "foo"]).unwrap();
-        assert_eq!(cli.nproc, 2);
+        assert_eq!(cli.nproc, Some(Nproc::Count(2)));
         assert_eq!(cli.command, "foo".to_owned().into());
         assert!(cli.args.is_empty());
 
         let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--env-dir=./foo-env"]).unwrap();
-        assert_eq!(cli.nproc, 2);
+        assert_eq!(cli.nproc, Some(Nproc::Count(2)));
         assert_eq!(cli.command, None);
+        assert_eq!(cli.namespace, None);
         assert!(cli.args.is_empty());
+
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--namespace=bar", "foo"]).unwrap();
+        assert_eq!(cli.namespace, "bar".to_owned().into());
+        assert_eq!(cli.command, "foo".to_owned().into());
+    }
+
+    #[test]
+    fn test_env_template_args() {
+        let cli = Cli::try_parse_from([
+            "pmi-k8s",
+            "--nproc=2",
+            "--env=CUDA_VISIBLE_DEVICES={local_rank}",
+            "--env-default=OMPI_MCA_foo=bar",
+            "foo",
+        ])
+        .unwrap();
+        let templates = cli.env_templates().unwrap();
+        assert_eq!(templates.overrides[0].key(), "CUDA_VISIBLE_DEVICES");
+        assert_eq!(templates.defaults[0].key(), "OMPI_MCA_foo");
+        assert_eq!(cli.command, "foo".to_owned().into());
+
+        assert!(Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--env={rank}", "foo"]).is_err());
+    }
+
+    #[test]
+    fn test_exec_args() {
+        let cli = Cli::try_parse_from(["pmi-k8s", "exec", "--env-dir=/mnt/env", "--", "foo", "-x"])
+            .unwrap();
+        assert_eq!(cli.nproc, None);
+        let Some(Commands::Exec(exec)) = cli.subcommand else {
+            panic!("expected exec subcommand");
+        };
+        assert_eq!(exec.env_dir, PathBuf::from("/mnt/env"));
+        assert_eq!(exec.command, ["foo", "-x"]);
+
+        assert!(Cli::try_parse_from(["pmi-k8s", "exec", "--env-dir=/mnt/env"]).is_err());
+        assert!(Cli::try_parse_from(["pmi-k8s", "foo"]).is_err());
+    }
+
+    #[test]
+    fn test_topology_args() {
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "foo"]).unwrap();
+        assert_eq!(cli.topology, TopologyKind::Job);
+
+        let cli =
+            Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--topology=jobset", "foo"]).unwrap();
+        assert_eq!(cli.topology, TopologyKind::JobSet);
+
+        assert!(
+            Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--topology=selector", "foo"]).is_err()
+        );
+        assert!(
+            Cli::try_parse_from([
+                "pmi-k8s",
+                "--nproc=2",
+                "--topology=selector",
+                "--selector=app=mpi",
+                "--index-label=index",
+                "--nnodes=4",
+                "foo",
+            ])
+            .is_err()
+        );
+        let cli = Cli::try_parse_from([
+            "pmi-k8s",
+            "--nproc=2",
+            "--topology=selector",
+            "--selector=app=mpi",
+            "--index-label=index",
+            "--nnodes=4",
+            "--namespace=my-job",
+            "foo",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.topology_source(),
+            peer::topology::Source::Selector { nnodes: 4, .. }
+        ));
+    }
+
+    #[test]
+    fn test_secret_file() {
+        let tmpdir = tempdir::TempDir::new("secret-test").unwrap();
+        let path = tmpdir.path().join("secret");
+        let arg = format!("--secret-file={}", path.display());
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", &arg, "foo"]).unwrap();
+
+        std::fs::write(&path, "").unwrap();
+        assert!(cli.secret().is_err());
+        std::fs::write(&path, "\n").unwrap();
+        assert!(cli.secret().is_err());
+        std::fs::write(&path, "hunter2").unwrap();
+        let secret = cli.secret().unwrap().unwrap();
+        std::fs::write(&path, "hunter2\n").unwrap();
+        let trimmed = cli.secret().unwrap().unwrap();
+        assert_eq!(secret.derive(b"test"), trimmed.derive(b"test"));
+        std::fs::write(&path, "hunter2\n\n").unwrap();
+        let untrimmed = cli.secret().unwrap().unwrap();
+        assert_ne!(secret.derive(b"test"), untrimmed.derive(b"test"));
+
+        assert!(
+            Cli::try_parse_from(["pmi-k8s", "--nproc=2", &arg, "--secret-generate", "foo"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_backoff_args() {
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "foo"]).unwrap();
+        assert_eq!(cli.backoff(), net::Backoff::default());
+
+        let cli = Cli::try_parse_from([
+            "pmi-k8s",
+            "--nproc=2",
+            "--connect-backoff=100",
+            "--connect-backoff-max=1000",
+            "foo",
+        ])
+        .unwrap();
+        assert_eq!(cli.backoff().initial, Duration::from_millis(100));
+        assert_eq!(cli.backoff().max, Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_elastic_args() {
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "foo"]).unwrap();
+        assert_eq!(cli.min_nodes, None);
+        assert_eq!(cli.max_nodes, None);
+
+        let cli = Cli::try_parse_from([
+            "pmi-k8s",
+            "--nproc=2",
+            "--min-nodes=2",
+            "--max-nodes=8",
+            "foo",
+        ])
+        .unwrap();
+        assert_eq!(cli.min_nodes, Some(2));
+        assert_eq!(cli.max_nodes, Some(8));
+
+        assert!(Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--min-nodes=2", "foo"]).is_err());
+    }
+
+    #[test]
+    fn test_apps() {
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "foo", "-n", "1"]).unwrap();
+        let apps = cli.apps(4).unwrap();
+        assert_eq!(
+            apps,
+            [AppContext {
+                nproc: 4,
+                command: "foo".to_owned(),
+                args: vec!["-n".to_owned(), "1".to_owned()],
+                envs: vec![],
+            }]
+        );
+
+        let cli = Cli::try_parse_from([
+            "pmi-k8s",
+            "--nproc=4",
+            "--",
+            "-n",
+            "1",
+            "-x",
+            "A=b=c",
+            "a",
+            "--x",
+            ":",
+            "b",
+            "y",
+        ])
+        .unwrap();
+        let apps = cli.apps(8).unwrap();
+        assert_eq!(
+            apps,
+            [
+                AppContext {
+                    nproc: 1,
+                    command: "a".to_owned(),
+                    args: vec!["--x".to_owned()],
+                    envs: vec![("A".to_owned(), "b=c".to_owned())],
+                },
+                AppContext {
+                    nproc: 7,
+                    command: "b".to_owned(),
+                    args: vec!["y".to_owned()],
+                    envs: vec![],
+                },
+            ]
+        );
+        assert_eq!(AppContext::for_rank(&apps, 0).unwrap().0, 0);
+        assert_eq!(AppContext::for_rank(&apps, 7).unwrap().0, 1);
+        assert!(AppContext::for_rank(&apps, 8).is_none());
+
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--env-dir=./foo-env"]).unwrap();
+        assert!(cli.apps(4).unwrap().is_empty());
+
+        // Without `-n` or `-x` first, `:` is passed to the program
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "a", ":", "b"]).unwrap();
+        let apps = cli.apps(4).unwrap();
+        assert_eq!(apps.len(), 1);
+        assert_eq!(apps[0].args, [":", "b"]);
+    }
+
+    #[test]
+    fn test_apps_err() {
+        let cli = Cli::try_parse_from([
+            "pmi-k8s",
+            "--nproc=2",
+            "--",
+            "-n",
+            "1",
+            "a",
+            ":",
+            "-n",
+            "1",
+            "b",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.apps(4),
+            Err(AppError::SizeMismatch {
+                requested: 2,
+                available: 4
+            })
+        ));
+
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "-x", "A=1", "a", ":", "b"])
+            .unwrap();
+        assert!(matches!(cli.apps(4), Err(AppError::AmbiguousCount)));
+
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "-n", "x", "a"]).unwrap();
+        assert!(matches!(cli.apps(4), Err(AppError::InvalidCount(_))));
+
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "-n", "1", "a", ":"]).unwrap();
+        assert!(matches!(cli.apps(4), Err(AppError::MissingCommand)));
+
+        let cli = Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "-n"]).unwrap();
+        assert!(matches!(cli.apps(4), Err(AppError::MissingValue(o)) if o == "-n"));
+        let cli =
+            Cli::try_parse_from(["pmi-k8s", "--nproc=2", "--", "-x", "A=1", "--env"]).unwrap();
+        assert!(matches!(cli.apps(4), Err(AppError::MissingValue(o)) if o == "--env"));
+
+        let cli = Cli::try_parse_from([
+            "pmi-k8s",
+            "--nproc=2",
+            "--",
+            "-n",
+            "4294967295",
+            "a",
+            ":",
+            "-n",
+            "1",
+            "b",
+        ])
+        .unwrap();
+        assert!(matches!(cli.apps(4), Err(AppError::TooManyProcesses)));
     }
 }
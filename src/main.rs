@@ -1,93 +1,534 @@
 use futures::{
     StreamExt, TryStreamExt,
     future::{self, Either},
-    stream::{self, FuturesUnordered},
+    stream::FuturesUnordered,
 };
-use std::{net, pin::pin};
+use std::{collections::HashSet, env, ffi, io, net, pin::pin, sync::Arc, time::Duration};
 use tempdir::TempDir;
 
-use anyhow::Error;
-use clap::Parser;
+use anyhow::{Error, anyhow};
 use tokio::{
-    fs,
     process::Command,
     signal::unix::{SignalKind, signal},
+    sync::{broadcast, mpsc, watch},
+    time,
 };
+use tracing::{info, warn};
 
 use pmi_k8s::{
-    Cli,
+    AppContext, Commands, Nproc,
+    config::Config,
+    credential::NetCredentials,
+    envfile, exec,
     fence::NetFence,
+    handshake::{Fingerprint, Handshake},
+    health::{Check, Health},
     modex::NetModex,
-    peer::{KubernetesPeers, PeerDiscovery, k8s::PORT},
-    pmix,
+    peer::{
+        Endpoint, KubernetesPeers, PeerDiscovery,
+        events::{EventRecorder, Milestone},
+    },
+    pmix::{self, info::Key},
+    query::{NetQuery, ProcEntry},
+    resources, telemetry,
+    template::RankInfo,
+    tls::{Ca, Tls},
+    wire,
 };
 
 const WILDCARD: net::IpAddr = net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0));
+/// How long to wait before retrying after failing to watch the job's size.
+const RESIZE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Error> {
-    let args = Cli::parse();
-    let namespace = c"foo";
+    let config = Config::parse()?;
+    if config.cli.print_config {
+        print!("{}", config);
+        return Ok(());
+    }
+    let args = config.cli;
+    telemetry::init_logging(args.log_format, args.log_level.as_deref())?;
+    if let Some(Commands::Exec(exec)) = &args.subcommand {
+        let (command, argv) = exec.command.split_first().expect("required by clap");
+        let code = exec::run(&exec.env_dir, command, argv).await?;
+        std::process::exit(code);
+    }
+    let nproc = match args.nproc.expect("required by clap") {
+        Nproc::Count(nproc) => nproc,
+        Nproc::Auto => {
+            let (nproc, source) = resources::detect(args.nproc_file.as_deref())?;
+            info!(nproc, %source, "detected number of processes");
+            nproc
+        }
+    };
 
-    let peers = KubernetesPeers::new(args.nproc).await?;
-    let fence = NetFence::new(net::SocketAddr::new(WILDCARD, PORT), &peers).await?;
-    let modex = NetModex::new(net::SocketAddr::new(WILDCARD, PORT + 1), &peers).await?;
+    if let Some(addr) = args.metrics_addr {
+        telemetry::install_metrics(addr)?;
+    }
+    let health = Arc::new(Health::default());
+    if let Some(addr) = args.health_addr {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        tokio::spawn(health.clone().serve(listener));
+    }
+    if let Some(env_dir) = &args.env_dir {
+        // Don't let processes start from a previous run's environment
+        exec::clean(env_dir).await?;
+    }
 
-    let hostname = nix::unistd::gethostname()?;
+    let mut peers = KubernetesPeers::new(nproc, args.topology_source())
+        .await?
+        .with_port(args.port);
+    if let Some(namespace) = &args.namespace {
+        peers = peers.with_namespace(namespace.clone());
+    }
+    if let (Some(min_nodes), Some(max_nodes)) = (args.min_nodes, args.max_nodes) {
+        peers = peers.with_elastic(min_nodes, max_nodes)?;
+    }
+    if let Some(timeout) = args.discovery_timeout {
+        peers = peers.with_discovery_timeout(Duration::from_secs(timeout));
+    }
+    let recorder = args
+        .events
+        .then(|| peers.recorder(args.annotate_phase))
+        .flatten();
+    if args.events && recorder.is_none() {
+        warn!("no object to report events on, set POD_NAME");
+    }
+    let namespace = ffi::CString::new(peers.namespace())?;
+    let job_id = ffi::CString::new(peers.job_name())?;
+    let (dump_tx, _) = broadcast::channel(1);
+    let mut fence = NetFence::new(
+        net::SocketAddr::new(WILDCARD, peers.port(Endpoint::Fence)),
+        &peers,
+    )
+    .await?
+    .with_dump(dump_tx.subscribe());
+    let mut modex = NetModex::new(
+        net::SocketAddr::new(WILDCARD, peers.port(Endpoint::Modex)),
+        &peers,
+    )
+    .await?
+    .with_dump(dump_tx.subscribe());
+    let mut credentials = NetCredentials::new(
+        net::SocketAddr::new(WILDCARD, peers.port(Endpoint::Credential)),
+        &peers,
+    )
+    .await?;
+    let mut query = NetQuery::new(
+        net::SocketAddr::new(WILDCARD, peers.port(Endpoint::Query)),
+        &peers,
+        peers.namespace(),
+    )
+    .await?;
+    let hostname = peers.hostname();
     let hostnames = peers.hostnames().collect::<Vec<_>>();
+    let job_size = hostnames.len() as u32 * nproc as u32;
+    let universe_size = args
+        .max_nodes
+        .map_or(job_size, |max_nodes| max_nodes * nproc as u32);
+    let fingerprint = Fingerprint::new(
+        peers.namespace(),
+        nproc,
+        hostnames.len() as u32,
+        universe_size,
+    );
+    let mut handshake = Handshake::new(
+        net::SocketAddr::new(WILDCARD, peers.port(Endpoint::Handshake)),
+        &peers,
+        peers.node_rank(),
+        fingerprint,
+    )
+    .await?;
+    if let Some(timeout) = args.discovery_timeout {
+        handshake = handshake.with_timeout(Duration::from_secs(timeout));
+    }
+    let secret = match args.secret()? {
+        Some(secret) => Some(secret),
+        None if args.secret_generate => {
+            let mut secret = vec![0; 32];
+            getrandom::fill(&mut secret)?;
+            Some(wire::Secret::new(peers.shared_secret(secret).await?))
+        }
+        None => None,
+    };
+    if let Some(secret) = secret {
+        fence = fence.with_secret(secret.clone());
+        modex = modex.with_secret(secret.clone());
+        credentials = credentials.with_secret(secret.clone());
+        query = query.with_secret(secret.clone());
+        handshake = handshake.with_secret(secret);
+    }
+    let tls = match &args.tls_dir {
+        Some(dir) => Some(Tls::load(dir)?),
+        None if args.tls_generate => Some(peers.shared_ca(Ca::generate()?).await?.issue()?),
+        None => None,
+    };
+    if let Some(tls) = tls {
+        fence = fence.with_tls(tls.clone());
+        modex = modex.with_tls(tls.clone());
+        credentials = credentials.with_tls(tls.clone());
+        query = query.with_tls(tls.clone());
+        handshake = handshake.with_tls(tls);
+    }
+    let backoff = args.backoff();
+    fence = fence.with_backoff(backoff);
+    modex = modex.with_backoff(backoff);
+    credentials = credentials.with_backoff(backoff);
+    query = query.with_backoff(backoff);
+    handshake = handshake.with_backoff(backoff);
+    if let Some(threshold) = args.compress_above {
+        fence = fence.with_compression(threshold);
+        modex = modex.with_compression(threshold);
+    }
+    health.set(Check::Bound);
+
+    let apps = args.apps(job_size)?;
+    let templates = args.env_templates()?;
+    let pmix_apps = if apps.is_empty() {
+        vec![pmix::server::App {
+            size: job_size,
+            argv: None,
+        }]
+    } else {
+        apps.iter()
+            .map(|app| {
+                let argv = std::iter::once(&app.command)
+                    .chain(&app.args)
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Ok::<_, ffi::NulError>(pmix::server::App {
+                    size: app.nproc,
+                    argv: Some(ffi::CString::new(argv)?),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    // Only the initial wait for the world is subject to --discovery-timeout
+    let nnodes = peers.wait_for_peers().await?;
+    health.set(Check::Discovered);
+    if let Some(recorder) = &recorder {
+        recorder.record(Milestone::PeersDiscovered { nnodes }).await;
+    }
+    // Don't start any processes until every pod agrees on the configuration
+    handshake.run().await?;
 
-    let tempdir = TempDir::new("pmi-k8s")?;
-    let (s, e) = pmix::server::Server::init(tempdir.path(), &hostname)?;
-    let ns = pmix::server::Namespace::register(&s, namespace, &hostnames, args.nproc)?;
+    let tempdir = match &args.tmpdir {
+        Some(dir) => TempDir::new_in(dir, "pmi-k8s")?,
+        None => TempDir::new("pmi-k8s")?,
+    };
+    let (s, mut e) = pmix::server::Server::init(
+        tempdir.path(),
+        ffi::OsStr::new(&hostname),
+        args.tool_rendezvous.as_deref(),
+    )?;
+    if let Some(recorder) = &recorder {
+        recorder.record(Milestone::ServerInitialized).await;
+    }
+    let ns = pmix::server::Namespace::register(
+        &s,
+        &namespace,
+        &job_id,
+        &hostnames,
+        nproc,
+        &pmix_apps,
+        universe_size,
+    )?;
     let clients = peers
         .local_ranks()
         .map(|i| pmix::server::Client::register(&ns, i))
         .collect::<Result<Vec<_>, _>>()?;
+    let table = query.table();
+    for rank in peers.local_ranks() {
+        let executable = AppContext::for_rank(&apps, rank).map(|(_, app)| app.command.clone());
+        table.insert(ProcEntry {
+            namespace: peers.namespace(),
+            rank,
+            hostname: hostname.clone(),
+            executable: executable.unwrap_or_default(),
+            pid: None,
+            exit_code: None,
+        });
+    }
+    health.set(Check::Initialized);
 
-    let run = pin!(e.run(fence, modex));
+    let resize = async {
+        if args.min_nodes.is_none() {
+            return future::pending().await;
+        }
 
+        let mut nnodes = pin!(peers.watch_nnodes());
+        let mut current = peers.nnodes();
+        while let Some(n) = nnodes.next().await {
+            // The watcher recovers by itself, so just wait for the next update
+            let n = match n {
+                Ok(n) => n,
+                Err(err) => {
+                    warn!(%err, "unable to watch the job's size");
+                    time::sleep(RESIZE_RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+            if n == current {
+                continue;
+            }
+            info!(from = current, to = n, "job resized");
+            if n < args.min_nodes.unwrap_or_default() {
+                warn!(nnodes = n, "job shrunk below its minimum size");
+            }
+            if n > args.max_nodes.unwrap_or(u32::MAX) {
+                warn!(nnodes = n, "job grew beyond its maximum size");
+            }
+            current = n;
+            ns.notify(
+                pmix::server::EVENT_JOB_RESIZED,
+                &[pmix::info::NumNodes::info(&n)],
+            )?;
+        }
+        Ok::<_, Error>(())
+    };
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+    let dump = async {
+        while sigusr2.recv().await.is_some() {
+            info!("dumping in-flight fences and modex requests");
+            dump_tx.send(()).unwrap_or_default();
+        }
+        Ok::<_, Error>(())
+    };
+    let report = report(recorder.as_ref(), &peers, fence.completed());
+    let aborted = aborted(recorder.as_ref(), e.aborts());
+    let client_events = e.clients();
+    let credentials = credentials.serve(e.credentials());
+    let credentials = async { Ok::<_, Error>(credentials.await?) };
+    let query = query.serve(e.queries());
+    let query = async { Ok::<_, Error>(query.await?) };
+    let run = pin!(async {
+        let background = async {
+            futures::try_join!(resize, report, aborted, dump, credentials, query).map(|_| ())
+        };
+        match future::select(pin!(e.run(fence, modex)), pin!(background)).await {
+            Either::Left((result, _)) => Ok::<_, Error>(result?),
+            Either::Right((result, _)) => result,
+        }
+    });
+
+    let nspace = peers.namespace();
+    let inherited = env::vars_os().map(|(k, _)| k).collect::<HashSet<_>>();
     let envs = clients
         .iter()
-        .map(|c| c.envs())
+        .zip(peers.local_ranks())
+        .enumerate()
+        .map(|(local_rank, (client, rank))| {
+            let mut vars = client
+                .envs()?
+                .iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect::<Vec<_>>();
+            let info = RankInfo {
+                rank,
+                local_rank: local_rank as u32,
+                node_rank: peers.node_rank(),
+                namespace: &nspace,
+            };
+            let app_envs = AppContext::for_rank(&apps, rank)
+                .map(|(_, app)| app.envs.as_slice())
+                .unwrap_or_default();
+            templates.apply(&mut vars, &info, |key: &ffi::OsStr| {
+                inherited.contains(key) || app_envs.iter().any(|(k, _)| key == k.as_str())
+            });
+            Ok::<_, Error>(vars)
+        })
         .collect::<Result<Vec<_>, _>>()?;
 
-    if let Some(env_path) = args.env_dir {
-        stream::iter(envs.iter())
-            .enumerate()
-            .map(Ok::<_, Error>)
-            .try_for_each(async |(i, envs)| {
-                let env_path = env_path.join(format!("{}.env", i));
-                let mut file = fs::File::create(&env_path).await?;
-                Ok(envs.write(&mut file).await?)
+    if let Some(env_dir) = &args.env_dir {
+        let encoded = peers
+            .local_ranks()
+            .zip(&envs)
+            .map(|(rank, envs)| {
+                let vars = envs.iter().map(|(k, v)| (k.as_os_str(), v.as_os_str()));
+                Ok((rank, envfile::encode(vars, args.env_format)?))
             })
-            .await?;
-
-        fs::File::create(env_path.join("ready")).await?;
+            .collect::<Result<Vec<_>, io::Error>>()?;
+        let manifest = exec::publish(env_dir, nspace.clone(), args.env_format, encoded).await?;
+        info!(
+            generation = manifest.generation,
+            "published environment files"
+        );
     }
+    health.set(Check::EnvWritten);
 
-    let rcs = if let Some(command) = args.command {
+    let rcs = if !apps.is_empty() {
         Either::Left(
-            envs.into_iter()
-                .map(|envs| Command::new(&command).envs(&envs).args(&args.args).spawn())
-                .map(async |spawn| Ok::<_, Error>(spawn?.wait().await?))
+            peers
+                .local_ranks()
+                .zip(envs)
+                .map(|(rank, envs)| {
+                    #[allow(clippy::unwrap_used, reason = "apps cover every rank in the job")]
+                    let (_, app) = AppContext::for_rank(&apps, rank).unwrap();
+                    let spawn = Command::new(&app.command)
+                        .envs(envs)
+                        .envs(app.envs.iter().map(|(k, v)| (k, v)))
+                        .args(&app.args)
+                        // Don't leave local ranks running if the server fails
+                        .kill_on_drop(true)
+                        .spawn();
+                    (rank, spawn)
+                })
+                .map(async |(rank, spawn)| {
+                    let mut child = spawn?;
+                    table.started(rank, child.id());
+                    let status = child.wait().await?;
+                    table.exited(rank, status);
+                    Ok::<_, Error>(status)
+                })
                 .collect::<FuturesUnordered<_>>()
                 .try_collect::<Vec<_>>(),
         )
     } else {
+        // The processes run elsewhere, but connect to our PMIx server
         let mut sigterm = signal(SignalKind::terminate())?;
+        let finalized = wait_finalized(client_events, peers.local_ranks());
+        let native_sidecar = args.native_sidecar;
+        let finalized = async move {
+            let result = finalized.await;
+            if !native_sidecar {
+                return result;
+            }
+            if let Err(err) = result {
+                warn!(%err, "local clients failed");
+            }
+            // Exiting would only get us restarted, so wait for SIGTERM
+            future::pending().await
+        };
         Either::Right(pin!(async move {
-            sigterm.recv().await;
-            Ok(Vec::new())
+            match future::select(pin!(sigterm.recv()), pin!(finalized)).await {
+                Either::Left(_) => Ok(Vec::new()),
+                Either::Right((result, _)) => result.map(|()| Vec::new()),
+            }
         }))
     };
 
     let rcs = match future::select(rcs, run).await {
-        Either::Left((rcs, _)) => rcs?,
-        Either::Right((Ok(()), rcs)) => rcs.await?,
-        Either::Right((Err(err), _)) => Err(err)?,
+        Either::Left((rcs, _)) => rcs,
+        Either::Right((Ok(()), rcs)) => rcs.await,
+        Either::Right((Err(err), _)) => Err(err),
     };
+    if let Some(env_dir) = &args.env_dir {
+        if let Err(err) = exec::clean(env_dir).await {
+            warn!(%err, "unable to remove environment files");
+        }
+    }
+    let rcs = rcs?;
 
     assert!(rcs.iter().all(|rc| rc.success()));
 
     Ok(())
 }
+
+/// Wait until each of `ranks` has finalized or disconnected. Fails if any
+/// disconnected without finalizing.
+async fn wait_finalized(
+    mut events: mpsc::UnboundedReceiver<pmix::globals::ClientEvent>,
+    ranks: impl IntoIterator<Item = u32>,
+) -> Result<(), Error> {
+    use pmix::globals::ClientEvent;
+
+    let mut pending = ranks.into_iter().collect::<HashSet<_>>();
+    let mut lost = HashSet::new();
+    loop {
+        let event = if pending.is_empty() {
+            // A lost connection may be reported just after the client finalized
+            match events.try_recv() {
+                Ok(event) => event,
+                Err(_) => break,
+            }
+        } else {
+            match events.recv().await {
+                Some(event) => event,
+                None => return future::pending().await,
+            }
+        };
+        match event {
+            ClientEvent::Connected { .. } => {}
+            ClientEvent::Finalized { proc } => {
+                pending.remove(&proc.rank);
+            }
+            ClientEvent::Lost { proc } => {
+                pending.remove(&proc.rank);
+                lost.insert(proc.rank);
+            }
+        }
+    }
+
+    if lost.is_empty() {
+        info!("all local clients finalized");
+        Ok(())
+    } else {
+        let mut lost = lost.into_iter().collect::<Vec<_>>();
+        lost.sort();
+        Err(anyhow!("ranks {:?} exited without finalizing", lost))
+    }
+}
+
+/// Fail once any local client calls `PMIx_Abort`, which stops the server and
+/// kills the local ranks.
+async fn aborted(
+    recorder: Option<&EventRecorder>,
+    mut aborts: mpsc::UnboundedReceiver<pmix::globals::AbortEvent>,
+) -> Result<(), Error> {
+    let Some(abort) = aborts.recv().await else {
+        return future::pending().await;
+    };
+    let err = anyhow!(
+        "rank {} aborted with status {}: {}",
+        abort.proc.rank,
+        abort.status,
+        abort.message
+    );
+    if let Some(recorder) = recorder {
+        let milestone = Milestone::RankAborted {
+            rank: abort.proc.rank,
+            status: abort.status,
+            message: abort.message,
+        };
+        recorder.record(milestone).await;
+    }
+    Err(err)
+}
+
+/// Publish Events as the job progresses, until the server exits. Failing to
+/// report is not fatal to the job.
+async fn report(
+    recorder: Option<&EventRecorder>,
+    peers: &KubernetesPeers,
+    mut completed: watch::Receiver<u64>,
+) -> Result<(), Error> {
+    let Some(recorder) = recorder else {
+        return future::pending().await;
+    };
+
+    let progress = async {
+        if completed.wait_for(|n| *n > 0).await.is_ok() {
+            recorder.record(Milestone::FenceCompleted).await;
+        }
+        Ok::<_, Error>(())
+    };
+    let lost = async {
+        // Every pod sees the same losses, so only node 0 reports them
+        if peers.node_rank() != 0 {
+            return Ok(());
+        }
+        let mut lost = pin!(peers.watch_lost());
+        while let Some(node_rank) = lost.try_next().await? {
+            recorder.record(Milestone::PeerLost { node_rank }).await;
+        }
+        Ok::<_, Error>(())
+    };
+
+    if let Err(err) = futures::try_join!(progress, lost) {
+        warn!(%err, "stopped reporting events");
+    }
+    future::pending().await
+}
@@ -1,19 +1,24 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::ffi;
 use std::pin::pin;
-use std::{mem, net::SocketAddr};
+use std::time::Instant;
+use std::{io, mem, net::SocketAddr};
 
 use futures::FutureExt;
-use futures::{StreamExt, future::select};
+use futures::{
+    StreamExt,
+    future::{join, select},
+};
+use metrics::{counter, histogram};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net,
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot},
 };
 use tokio_stream::wrappers::{TcpListenerStream, UnboundedReceiverStream};
-use tracing::warn;
+use tracing::{Span, debug, field, info, instrument, warn};
 
-use crate::net::connect_peer;
-use crate::pmix::{PmixError, PmixStatus};
+use crate::pmix::{PmixError, PmixStatus, ProcDisplay};
 use crate::{
     ModexError,
     peer::{Endpoint, PeerDiscovery},
@@ -23,9 +28,12 @@ use crate::{
         slice_from_raw_parts, sys, u8_to_char,
     },
 };
+use crate::{net::Backoff, telemetry, tls::Tls, wire};
 
 type ModexResponse = Result<Vec<u8>, PmixError>;
 
+const STATUS_LEN: usize = mem::size_of::<sys::pmix_status_t>();
+
 unsafe extern "C" fn response(
     status: sys::pmix_status_t,
     data: *mut std::ffi::c_char,
@@ -52,10 +60,64 @@ type RequestFn = unsafe extern "C" fn(
     cbdata: *mut ffi::c_void,
 ) -> sys::pmix_status_t;
 
+struct PendingRequest {
+    proc: sys::pmix_proc_t,
+    peer: Option<SocketAddr>,
+    started: Instant,
+}
+
+/// Direct modex requests sent to peers and not yet answered, for debugging.
+#[derive(Default)]
+struct PendingRequests(RefCell<(u64, BTreeMap<u64, PendingRequest>)>);
+
+impl PendingRequests {
+    fn insert(&self, proc: sys::pmix_proc_t) -> u64 {
+        let mut guard = self.0.borrow_mut();
+        let (next, requests) = &mut *guard;
+        let id = *next;
+        *next += 1;
+        let request = PendingRequest {
+            proc,
+            peer: None,
+            started: Instant::now(),
+        };
+        requests.insert(id, request);
+        id
+    }
+
+    fn set_peer(&self, id: u64, peer: SocketAddr) {
+        let mut guard = self.0.borrow_mut();
+        if let Some(request) = guard.1.get_mut(&id) {
+            request.peer = Some(peer);
+        }
+    }
+
+    fn remove(&self, id: u64) -> Option<PendingRequest> {
+        let mut guard = self.0.borrow_mut();
+        guard.1.remove(&id)
+    }
+
+    fn log(&self) {
+        let guard = self.0.borrow();
+        info!(count = guard.1.len(), "pending modex requests");
+        for request in guard.1.values() {
+            info!(
+                proc = %ProcDisplay(&request.proc),
+                peer = ?request.peer,
+                waiting = ?request.started.elapsed(),
+                "pending modex request"
+            );
+        }
+    }
+}
+
 pub struct NetModex<'a, D: PeerDiscovery> {
     discovery: &'a D,
     listener: net::TcpListener,
     request_fn: RequestFn,
+    pending: PendingRequests,
+    dump: Option<broadcast::Receiver<()>>,
+    wire: wire::Options,
 }
 
 impl<'a, D: PeerDiscovery> NetModex<'a, D> {
@@ -73,9 +135,42 @@ impl<'a, D: PeerDiscovery> NetModex<'a, D> {
             listener,
             discovery,
             request_fn,
+            pending: Default::default(),
+            dump: None,
+            wire: Default::default(),
         })
     }
 
+    /// Authenticate connections to and from peers with `secret`.
+    pub fn with_secret(mut self, secret: wire::Secret) -> Self {
+        self.wire.secret = Some(secret);
+        self
+    }
+
+    /// Encrypt connections to and from peers with `tls`.
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.wire.tls = Some(tls);
+        self
+    }
+
+    /// Retry connecting to peers that aren't listening yet with `backoff`.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.wire.backoff = backoff;
+        self
+    }
+
+    /// Compress responses of at least `threshold` bytes.
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.wire.compress_above = Some(threshold);
+        self
+    }
+
+    /// Log all pending direct modex requests whenever `trigger` fires.
+    pub fn with_dump(mut self, trigger: broadcast::Receiver<()>) -> Self {
+        self.dump = Some(trigger);
+        self
+    }
+
     pub fn addr(&self) -> SocketAddr {
         #[allow(clippy::unwrap_used, reason = "We know we have a socket bound")]
         self.listener.local_addr().unwrap()
@@ -97,35 +192,60 @@ impl<'a, D: PeerDiscovery> NetModex<'a, D> {
         sys::pmix_proc_t { rank, nspace }
     }
 
+    #[instrument(
+        level = "debug",
+        name = "modex_request",
+        skip_all,
+        fields(proc = %ProcDisplay(&proc), peer = field::Empty),
+    )]
     async fn request_data(
         discovery: &'a D,
+        pending: &PendingRequests,
+        id: u64,
         proc: sys::pmix_proc_t,
+        options: &wire::Options,
     ) -> Result<Vec<u8>, ModexError<D::Error>> {
+        counter!(telemetry::DMODEX_ISSUED).increment(1);
         let req = Self::serialize_proc(proc);
         let addr = discovery
             .peer(&proc, Endpoint::Modex)
             .await
             .map_err(ModexError::Peer)?;
-
-        let mut s = connect_peer(&addr).await?;
-        s.write_all(&req).await?;
-        let mut status = [0; _];
-        s.read_exact(&mut status).await?;
+        Span::current().record("peer", field::display(&addr));
+        pending.set_peer(id, addr);
+
+        let mut s = wire::connect(&addr, Endpoint::Modex, options).await?;
+        wire::write_frame(&mut s, &req).await?;
+        let mut data = s.read_payload().await?;
+        let status = data.first_chunk::<STATUS_LEN>().copied().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "modex response too short")
+        })?;
         PmixStatus(sys::pmix_status_t::from_be_bytes(status)).check()?;
-
-        let mut data = Vec::new();
-        s.read_to_end(&mut data).await?;
+        data.drain(..STATUS_LEN);
+        debug!(bytes = data.len(), "received modex data");
         Ok(data)
     }
 
+    #[instrument(
+        level = "debug",
+        name = "modex_respond",
+        skip_all,
+        fields(peer = ?c.peer_addr().ok(), proc = field::Empty),
+    )]
     async fn respond(
-        mut c: net::TcpStream,
+        c: net::TcpStream,
         request_fn: RequestFn,
+        options: &wire::Options,
     ) -> Result<(), ModexError<D::Error>> {
-        let mut buf = [0; _];
-        c.read_exact(&mut buf).await?;
+        let mut c = wire::accept(c, Endpoint::Modex, options).await?;
+        let buf = wire::read_frame(&mut c)
+            .await?
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid modex request"))?;
+        counter!(telemetry::DMODEX_SERVED).increment(1);
         let (tx, rx) = oneshot::channel::<ModexResponse>();
         let proc = Self::parse_proc(buf);
+        Span::current().record("proc", field::display(ProcDisplay(&proc)));
         let tx = Box::into_raw(Box::new(tx));
 
         // SAFETY: `request_fn` is PMIx_server_dmodex_request outside of tests.
@@ -142,41 +262,75 @@ impl<'a, D: PeerDiscovery> NetModex<'a, D> {
         match rx.await.expect("modex response never sent") {
             Ok(data) => {
                 let code = sys::PMIX_SUCCESS as sys::pmix_status_t;
-                c.write_all(&code.to_be_bytes()).await?;
-                Ok(c.write_all(&data).await?)
+                let response = [code.to_be_bytes().as_slice(), &data].concat();
+                c.write_payload(&wire::Payload::new(&response, options)?)
+                    .await?;
+                debug!(bytes = data.len(), "sent modex data");
+                Ok(())
             }
             Err(err @ PmixError(code)) => {
-                c.write_all(&code.to_be_bytes()).await?;
+                let response = code.to_be_bytes();
+                c.write_payload(&wire::Payload::new(&response, options)?)
+                    .await?;
                 Err(ModexError::Server(err))
             }
         }
     }
 
+    #[instrument(name = "modex", skip_all, fields(addr = %self.addr()))]
     pub async fn serve(
-        self,
+        mut self,
         events: mpsc::UnboundedReceiver<globals::DirectModexEvent>,
     ) -> Result<(), ModexError<D::Error>> {
-        let requests = UnboundedReceiverStream::new(events)
-            .map(|DirectModexEvent { proc, cb }| {
-                Self::request_data(self.discovery, proc).map(|r| (cb, r))
+        let mut dump = self.dump.take();
+        // Requests are recorded as soon as they arrive, rather than when
+        // there's room to send them, so that dumps include the queued ones too
+        let (queued_tx, queued_rx) = mpsc::unbounded_channel();
+        let pending = &self.pending;
+        let queue = async move {
+            let mut events = events;
+            while let Some(event) = events.recv().await {
+                let id = pending.insert(event.proc);
+                // The receiver is only dropped after this sender
+                let _ = queued_tx.send((id, event));
+            }
+        };
+        let requests = UnboundedReceiverStream::new(queued_rx)
+            .map(|(id, DirectModexEvent { proc, cb })| {
+                Self::request_data(self.discovery, &self.pending, id, proc, &self.wire)
+                    .map(move |r| (cb, r, id))
             })
             .buffer_unordered(8)
-            .for_each(async |(cb, result)| match result {
-                Ok(data) => cb.call(sys::PMIX_SUCCESS as sys::pmix_status_t, data),
-                Err(err) => {
-                    warn!(%err, "modex request");
-                    cb.call(sys::PMIX_ERROR as sys::pmix_status_t, Vec::new());
+            .for_each(async |(cb, result, id)| {
+                if let Some(request) = self.pending.remove(id) {
+                    histogram!(telemetry::DMODEX_DURATION).record(request.started.elapsed());
+                }
+                match result {
+                    Ok(data) => cb.call(sys::PMIX_SUCCESS as sys::pmix_status_t, data),
+                    Err(err) => {
+                        warn!(%err, "modex request");
+                        cb.call(sys::PMIX_ERROR as sys::pmix_status_t, Vec::new());
+                    }
                 }
             });
         let responses =
             TcpListenerStream::new(self.listener).for_each_concurrent(8, async |c| match c {
-                Ok(c) => Self::respond(c, self.request_fn)
+                Ok(c) => Self::respond(c, self.request_fn, &self.wire)
                     .await
                     .unwrap_or_else(|err| warn!(%err, "modex response")),
                 Err(err) => warn!(%err, "modex accept"),
             });
 
-        let ((), _) = select(pin!(requests), pin!(responses)).await.factor_first();
+        let dumps = async {
+            loop {
+                telemetry::dump_requested(&mut dump).await;
+                self.pending.log();
+            }
+        };
+
+        let requests = join(queue, requests).map(|_| ());
+        let background = select(pin!(responses), pin!(dumps)).map(|_| ());
+        let ((), _) = select(pin!(requests), background).await.factor_first();
         Ok(())
     }
 }
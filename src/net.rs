@@ -1,16 +1,73 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{iter, net::SocketAddr, time::Duration};
 
+use metrics::counter;
 use tokio::{io, net, time};
 
-pub async fn connect_peer(peer: &SocketAddr) -> Result<net::TcpStream, io::Error> {
+use crate::telemetry;
+
+/// How long to wait between attempts to connect to a peer that isn't listening
+/// yet (e.g. its pod is still starting). The delay doubles after each attempt,
+/// up to `max`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(250),
+            max: Duration::from_secs(4),
+        }
+    }
+}
+
+impl Backoff {
+    fn delays(&self) -> impl Iterator<Item = Duration> {
+        let max = self.max.max(self.initial);
+        iter::successors(Some(self.initial), move |delay| {
+            Some(delay.saturating_mul(2).min(max))
+        })
+    }
+}
+
+pub async fn connect_peer(
+    peer: &SocketAddr,
+    backoff: &Backoff,
+) -> Result<net::TcpStream, io::Error> {
+    let mut delays = backoff.delays();
     loop {
         match net::TcpStream::connect(peer).await {
             Ok(s) => break Ok(s),
             Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
-                // TODO: Proper backoff
-                time::sleep(Duration::from_millis(250)).await
+                counter!(telemetry::CONNECT_RETRIES).increment(1);
+                #[allow(clippy::unwrap_used, reason = "delays never end")]
+                time::sleep(delays.next().unwrap()).await
             }
             Err(e) => return Err(e),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(500),
+        };
+        let delays = backoff.delays().take(5).collect::<Vec<_>>();
+        assert_eq!(delays, [100, 200, 400, 500, 500].map(Duration::from_millis));
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::ZERO,
+        };
+        let delays = backoff.delays().take(2).collect::<Vec<_>>();
+        assert_eq!(delays, [100, 100].map(Duration::from_millis));
+    }
+}
@@ -0,0 +1,203 @@
+//! Explaining why a pod of the job has no address yet, when discovery times
+//! out.
+
+use k8s_openapi::api::core::v1::{ContainerStatus, Event, Pod};
+
+/// When `event` last happened. Events from the `events.k8s.io` API only set
+/// `event_time`.
+fn last_seen(event: &Event) -> Option<impl Ord> {
+    let last = event.last_timestamp.as_ref().map(|time| time.0);
+    last.or_else(|| event.event_time.as_ref().map(|time| time.0))
+}
+
+fn describe(reason: Option<&str>, message: Option<&str>) -> String {
+    match (reason, message) {
+        (Some(reason), Some(message)) => format!("{}: {}", reason, message),
+        (Some(s), None) | (None, Some(s)) => s.to_owned(),
+        (None, None) => "unknown reason".to_owned(),
+    }
+}
+
+/// Why `pod` isn't running, from its status and the most recent warning in
+/// its `events`.
+pub(super) fn pod_problem(pod: &Pod, events: &[Event]) -> String {
+    let status = pod.status.as_ref();
+    let unscheduled = status
+        .and_then(|s| s.conditions.as_ref())
+        .into_iter()
+        .flatten()
+        .find(|c| c.type_ == "PodScheduled" && c.status == "False");
+    let containers = status
+        .and_then(|s| s.init_container_statuses.as_ref())
+        .into_iter()
+        .chain(status.and_then(|s| s.container_statuses.as_ref()))
+        .flatten()
+        .collect::<Vec<&ContainerStatus>>();
+    let waiting = containers.iter().find_map(|c| {
+        let waiting = c.state.as_ref()?.waiting.as_ref()?;
+        Some((&c.name, waiting))
+    });
+    let terminated = containers.iter().find_map(|c| {
+        let terminated = c.state.as_ref()?.terminated.as_ref()?;
+        Some((&c.name, terminated))
+    });
+
+    let mut problem = if pod.metadata.deletion_timestamp.is_some() {
+        "pod is being deleted".to_owned()
+    } else if let Some(condition) = unscheduled {
+        format!(
+            "not scheduled: {}",
+            describe(condition.reason.as_deref(), condition.message.as_deref())
+        )
+    } else if let Some((name, terminated)) = terminated {
+        format!(
+            "container {} exited with code {}: {}",
+            name,
+            terminated.exit_code,
+            describe(terminated.reason.as_deref(), terminated.message.as_deref())
+        )
+    } else if let Some((name, waiting)) = waiting {
+        format!(
+            "container {} is waiting: {}",
+            name,
+            describe(waiting.reason.as_deref(), waiting.message.as_deref())
+        )
+    } else {
+        let phase = status.and_then(|s| s.phase.as_deref());
+        format!("pod is {}", phase.unwrap_or("Unknown"))
+    };
+
+    let warning = events
+        .iter()
+        .filter(|e| e.type_.as_deref() == Some("Warning"))
+        .max_by(|a, b| last_seen(a).cmp(&last_seen(b)));
+    if let Some(event) = warning {
+        problem.push_str(&format!(
+            " (last warning: {})",
+            describe(event.reason.as_deref(), event.message.as_deref())
+        ));
+    }
+    problem
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use k8s_openapi::{
+        api::core::v1::{ContainerState, ContainerStateWaiting, PodCondition, PodStatus},
+        apimachinery::pkg::apis::meta::v1::{MicroTime, Time},
+        jiff::Timestamp,
+    };
+
+    use super::*;
+
+    fn pod(status: PodStatus) -> Pod {
+        Pod {
+            status: Some(status),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pod_problem() {
+        let unschedulable = pod(PodStatus {
+            phase: Some("Pending".to_owned()),
+            conditions: Some(vec![PodCondition {
+                type_: "PodScheduled".to_owned(),
+                status: "False".to_owned(),
+                reason: Some("Unschedulable".to_owned()),
+                message: Some("0/3 nodes are available: 3 Insufficient cpu.".to_owned()),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+        assert_eq!(
+            pod_problem(&unschedulable, &[]),
+            "not scheduled: Unschedulable: 0/3 nodes are available: 3 Insufficient cpu."
+        );
+
+        let pulling = pod(PodStatus {
+            phase: Some("Pending".to_owned()),
+            container_statuses: Some(vec![ContainerStatus {
+                name: "test".to_owned(),
+                state: Some(ContainerState {
+                    waiting: Some(ContainerStateWaiting {
+                        reason: Some("ImagePullBackOff".to_owned()),
+                        message: None,
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+        let events = [
+            Event {
+                type_: Some("Warning".to_owned()),
+                reason: Some("Failed".to_owned()),
+                message: Some("Failed to pull image \"foo\"".to_owned()),
+                ..Default::default()
+            },
+            Event {
+                type_: Some("Normal".to_owned()),
+                reason: Some("BackOff".to_owned()),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            pod_problem(&pulling, &events),
+            "container test is waiting: ImagePullBackOff \
+             (last warning: Failed: Failed to pull image \"foo\")"
+        );
+
+        let pending = pod(PodStatus {
+            phase: Some("Pending".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(pod_problem(&pending, &[]), "pod is Pending");
+    }
+
+    #[test]
+    fn test_latest_warning() {
+        let warning = |reason: &str| Event {
+            type_: Some("Warning".to_owned()),
+            reason: Some(reason.to_owned()),
+            ..Default::default()
+        };
+        let at = |second| Timestamp::from_second(second).unwrap();
+        let pending = pod(PodStatus {
+            phase: Some("Pending".to_owned()),
+            ..Default::default()
+        });
+
+        let events = [
+            Event {
+                last_timestamp: Some(Time(at(200))),
+                ..warning("Newer")
+            },
+            Event {
+                last_timestamp: Some(Time(at(100))),
+                ..warning("Older")
+            },
+        ];
+        assert_eq!(
+            pod_problem(&pending, &events),
+            "pod is Pending (last warning: Newer)"
+        );
+
+        let events = [
+            Event {
+                last_timestamp: Some(Time(at(100))),
+                ..warning("Older")
+            },
+            Event {
+                event_time: Some(MicroTime(at(200))),
+                ..warning("Newer")
+            },
+        ];
+        assert_eq!(
+            pod_problem(&pending, &events),
+            "pod is Pending (last warning: Newer)"
+        );
+    }
+}
@@ -2,7 +2,7 @@ use futures::{TryStreamExt, stream::FuturesUnordered};
 use notify::{self, Watcher};
 use std::{
     cell::RefCell,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     ffi, fs,
     io::{self, Write},
     net,
@@ -133,7 +133,7 @@ impl<'a> PeerDiscovery for DirectoryPeers<'a> {
         &self,
         procs: &[sys::pmix_proc_t],
         endpoint: Endpoint,
-    ) -> Result<Vec<net::SocketAddr>, Error> {
+    ) -> Result<HashMap<u32, net::SocketAddr>, Error> {
         if let [
             sys::pmix_proc_t {
                 rank: sys::PMIX_RANK_WILDCARD,
@@ -143,7 +143,9 @@ impl<'a> PeerDiscovery for DirectoryPeers<'a> {
         ] = procs
         {
             (0..self.nnodes)
-                .map(async |node_rank| self.node(node_rank, endpoint).await)
+                .map(async |node_rank| {
+                    Ok::<_, Error>((node_rank, self.node(node_rank, endpoint).await?))
+                })
                 .collect::<FuturesUnordered<_>>()
                 .try_collect()
                 .await
@@ -155,13 +157,19 @@ impl<'a> PeerDiscovery for DirectoryPeers<'a> {
 
             nodes
                 .into_iter()
-                .map(async |node_rank| self.node(node_rank, endpoint).await)
+                .map(async |node_rank| {
+                    Ok::<_, Error>((node_rank, self.node(node_rank, endpoint).await?))
+                })
                 .collect::<FuturesUnordered<_>>()
-                .try_collect::<Vec<_>>()
+                .try_collect()
                 .await
         }
     }
 
+    fn contains(&self, proc: &sys::pmix_proc_t) -> bool {
+        proc.rank <= sys::PMIX_RANK_VALID && proc.rank / (self.nproc as u32) < self.nnodes
+    }
+
     fn local_ranks(&self) -> impl Iterator<Item = u32> {
         let node_rank = self.node_rank.borrow().expect("Node is not registered");
         (node_rank * self.nproc as u32)..((node_rank + 1) * self.nproc as u32)
@@ -209,7 +217,7 @@ mod test {
             .peers(&wildcard, Endpoint::Fence)
             .await
             .unwrap()
-            .into_iter()
+            .into_values()
             .collect::<HashSet<_>>();
         assert_eq!(peers, expected);
 
@@ -223,7 +231,7 @@ mod test {
             .peers(&enumerated, Endpoint::Fence)
             .await
             .unwrap()
-            .into_iter()
+            .into_values()
             .collect::<HashSet<_>>();
         assert_eq!(peers, expected);
     }
@@ -0,0 +1,235 @@
+use k8s_openapi::{
+    api::core::v1::{ObjectReference, Pod},
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+};
+use kube::{
+    Api, Client,
+    api::{Patch, PatchParams},
+    runtime::events::{Event, EventType, Recorder, Reporter},
+};
+use tracing::warn;
+
+const CONTROLLER: &str = "pmi-k8s";
+const PHASE_ANNOTATION: &str = "pmi-k8s.io/phase";
+
+/// Points in the life of a PMIx server worth reporting to Kubernetes.
+pub enum Milestone {
+    ServerInitialized,
+    PeersDiscovered {
+        nnodes: u32,
+    },
+    FenceCompleted,
+    RankAborted {
+        rank: u32,
+        status: i32,
+        message: String,
+    },
+    PeerLost {
+        node_rank: u32,
+    },
+}
+
+impl Milestone {
+    /// The Event for this milestone, and the resulting phase of this pod.
+    fn event(&self, node_rank: u32) -> (Event, &'static str) {
+        let (type_, reason, action, note, phase) = match self {
+            Self::ServerInitialized => (
+                EventType::Normal,
+                "ServerInitialized",
+                "Initialize",
+                format!("node {}: PMIx server initialized", node_rank),
+                "Initialized",
+            ),
+            Self::PeersDiscovered { nnodes } => (
+                EventType::Normal,
+                "PeersDiscovered",
+                "Discover",
+                format!("node {}: discovered all {} nodes", node_rank, nnodes),
+                "Discovered",
+            ),
+            Self::FenceCompleted => (
+                EventType::Normal,
+                "FenceCompleted",
+                "Fence",
+                format!("node {}: completed first fence", node_rank),
+                "Running",
+            ),
+            Self::RankAborted {
+                rank,
+                status,
+                message,
+            } => (
+                EventType::Warning,
+                "RankAborted",
+                "Abort",
+                format!(
+                    "node {}: rank {} aborted with status {}: {}",
+                    node_rank, rank, status, message
+                ),
+                "Failed",
+            ),
+            Self::PeerLost { node_rank: lost } => (
+                EventType::Warning,
+                "PeerLost",
+                "Discover",
+                format!("node {}: lost peer node {}", node_rank, lost),
+                "Failed",
+            ),
+        };
+        let event = Event {
+            type_,
+            reason: reason.to_owned(),
+            note: Some(note),
+            action: action.to_owned(),
+            secondary: None,
+        };
+        (event, phase)
+    }
+}
+
+/// Publishes Events (and optionally a pod annotation) as the job progresses.
+/// Reporting is best-effort, failures are logged but otherwise ignored.
+pub struct EventRecorder {
+    recorder: Recorder,
+    regarding: ObjectReference,
+    pod: Option<ObjectReference>,
+    annotate: Option<(Api<Pod>, String)>,
+    node_rank: u32,
+}
+
+impl EventRecorder {
+    pub(super) fn new(
+        client: Client,
+        regarding: ObjectReference,
+        pod: Option<ObjectReference>,
+        annotate: Option<(Api<Pod>, String)>,
+        node_rank: u32,
+    ) -> Self {
+        let instance = pod.as_ref().and_then(|p| p.name.clone());
+        let reporter = Reporter {
+            controller: CONTROLLER.to_owned(),
+            instance,
+        };
+        Self {
+            recorder: Recorder::new(client, reporter),
+            regarding,
+            pod,
+            annotate,
+            node_rank,
+        }
+    }
+
+    pub async fn record(&self, milestone: Milestone) {
+        let (mut event, phase) = milestone.event(self.node_rank);
+        if self.pod.as_ref() != Some(&self.regarding) {
+            event.secondary = self.pod.clone();
+        }
+        if let Err(err) = self.recorder.publish(&event, &self.regarding).await {
+            warn!(%err, reason = %event.reason, "unable to publish event");
+        }
+
+        if let Some((pods, name)) = &self.annotate {
+            let patch = Pod {
+                metadata: ObjectMeta {
+                    annotations: Some([(PHASE_ANNOTATION.to_owned(), phase.to_owned())].into()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let params = PatchParams::default();
+            if let Err(err) = pods.patch(name, &params, &Patch::Merge(&patch)).await {
+                warn!(%err, phase, "unable to annotate pod");
+            }
+        }
+    }
+}
+
+/// A ServiceAccount, Role and RoleBinding named `name`, granting the
+/// permissions needed by pmi-k8s.
+pub fn rbac_manifest(name: &str) -> String {
+    format!(
+        r#"apiVersion: v1
+kind: ServiceAccount
+metadata:
+  name: {name}
+---
+apiVersion: rbac.authorization.k8s.io/v1
+kind: Role
+metadata:
+  name: {name}
+rules:
+  - apiGroups: [""]
+    resources: ["pods"]
+    verbs: ["get", "watch", "list", "patch"]
+  - apiGroups: ["batch"]
+    resources: ["jobs"]
+    verbs: ["get", "watch", "list"]
+  - apiGroups: ["apps"]
+    resources: ["statefulsets"]
+    verbs: ["get"]
+  - apiGroups: ["jobset.x-k8s.io"]
+    resources: ["jobsets"]
+    verbs: ["get"]
+  - apiGroups: [""]
+    resources: ["secrets"]
+    verbs: ["get", "create"]
+  - apiGroups: [""]
+    resources: ["events"]
+    verbs: ["list"]
+  - apiGroups: ["events.k8s.io"]
+    resources: ["events"]
+    verbs: ["create", "patch"]
+---
+apiVersion: rbac.authorization.k8s.io/v1
+kind: RoleBinding
+metadata:
+  name: {name}
+subjects:
+- kind: ServiceAccount
+  name: {name}
+roleRef:
+  apiGroup: rbac.authorization.k8s.io
+  kind: Role
+  name: {name}
+"#
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_milestone_event() {
+        let (event, phase) = Milestone::ServerInitialized.event(1);
+        assert_eq!(event.type_, EventType::Normal);
+        assert_eq!(
+            event.note.as_deref(),
+            Some("node 1: PMIx server initialized")
+        );
+        assert_eq!(phase, "Initialized");
+
+        let milestone = Milestone::RankAborted {
+            rank: 3,
+            status: -1,
+            message: "oops".to_owned(),
+        };
+        let (event, phase) = milestone.event(0);
+        assert_eq!(event.type_, EventType::Warning);
+        assert_eq!(event.reason, "RankAborted");
+        assert_eq!(
+            event.note.as_deref(),
+            Some("node 0: rank 3 aborted with status -1: oops")
+        );
+        assert_eq!(phase, "Failed");
+    }
+
+    #[test]
+    fn test_rbac_manifest() {
+        // The test deployment should always match the documented permissions
+        assert_eq!(
+            rbac_manifest("pmi-k8s-test"),
+            include_str!("../../tests/kustomization/base/rbac.yaml")
+        );
+    }
+}
@@ -1,37 +1,59 @@
-use futures::{StreamExt, TryStreamExt};
+use futures::{Stream, StreamExt, TryStreamExt, future};
 use std::{
-    collections::{HashMap, HashSet},
-    env, net,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env, ffi, net,
     pin::pin,
+    time::Duration,
 };
 
-use k8s_openapi::api::{batch::v1::Job, core::v1::Pod};
-use kube::{self, Api, Client, Config, runtime::watcher};
+use k8s_openapi::{
+    ByteString,
+    api::{
+        batch::v1::Job,
+        core::v1::{Event, ObjectReference, Pod, Secret},
+    },
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference},
+};
+use kube::{
+    self, Api, Client, Config,
+    api::{ListParams, PostParams},
+    runtime::{WatchStreamExt, watcher},
+};
+use metrics::counter;
 use thiserror::Error;
+use tokio::time;
+use tracing::warn;
 
-use crate::{peer::Endpoint, pmix::sys};
+use crate::{
+    peer::Endpoint,
+    pmix::{ProcDisplay, char_to_u8, sys},
+    telemetry, tls,
+};
 
-use super::PeerDiscovery;
+use super::{
+    PeerDiscovery, diagnose,
+    events::EventRecorder,
+    topology::{Ranks, Source, Topology, indexed_job_size, suspended},
+};
 
 pub struct KubernetesPeers {
+    client: Client,
     pods: kube::Api<Pod>,
-    job_name: String,
+    topology: Topology,
+    namespace: String,
     nproc: u16,
     nnodes: u32,
     node_rank: u32,
+    min_nodes: Option<u32>,
+    pod_name: Option<String>,
+    port: u16,
+    discovery_timeout: Option<Duration>,
 }
 
-const NAME_LABEL: &str = "batch.kubernetes.io/job-name";
-const RANK_LABEL: &str = "batch.kubernetes.io/job-completion-index";
-// TODO: Allow configuring
+/// The default port of the fence endpoint. The modex, credential, handshake and
+/// query endpoints use the four ports after it.
 pub const PORT: u16 = 5000;
 
-enum Ranks {
-    Single(u32),
-    Set(HashSet<u32>),
-    All,
-}
-
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("unable to detect Kubernetes configuration")]
@@ -46,88 +68,461 @@ pub enum Error {
     InvalidEnv(#[from] std::num::ParseIntError),
     #[error("missing expected field on Kubernetes object")]
     MissingField(&'static str),
+    #[error("unsupported Job shape: {0}")]
+    UnsupportedJob(String),
+    #[error("timed out waiting for pods: {}", .0.join("; "))]
+    DiscoveryTimeout(Vec<String>),
+    #[error("process {0} is not part of this job")]
+    UnknownProc(String),
 }
 
 impl KubernetesPeers {
-    pub async fn new(nproc: u16) -> Result<Self, Error> {
-        let job_name = env::var("JOB_NAME")?;
-        let node_rank = env::var("JOB_COMPLETION_INDEX")?.parse()?;
+    pub async fn new(nproc: u16, source: Source) -> Result<Self, Error> {
         let config = kube::Config::infer().await?;
-        Self::new_with_config(job_name, nproc, node_rank, config).await
+        Self::new_with_config(source, nproc, config).await
     }
 
-    async fn new_with_config(
-        job_name: String,
-        nproc: u16,
-        node_rank: u32,
-        config: Config,
-    ) -> Result<Self, Error> {
+    async fn new_with_config(source: Source, nproc: u16, config: Config) -> Result<Self, Error> {
         let client = Client::try_from(config)?;
         let pods = Api::<Pod>::default_namespaced(client.clone());
-        let jobs = Api::<Job>::default_namespaced(client);
-        let nnodes = jobs
-            .get(&job_name)
-            .await?
-            .spec
-            .and_then(|s| s.parallelism)
-            .ok_or(Error::MissingField("Job:spec.parallelism"))? as u32;
+        let pod_name = env::var("POD_NAME").ok();
+        let (topology, node_rank) = if let Source::Job = source {
+            let node_rank = env::var("JOB_COMPLETION_INDEX")?.parse()?;
+            (
+                Topology::resolve(source, client.clone(), None).await?,
+                node_rank,
+            )
+        } else {
+            let pod = pods.get(&env::var("POD_NAME")?).await?;
+            let topology = Topology::resolve(source, client.clone(), Some(&pod)).await?;
+            let node_rank = topology
+                .node_rank(&pod)
+                .ok_or(Error::MissingField("Pod:metadata.labels"))?;
+            (topology, node_rank)
+        };
+        let nnodes = topology.nnodes();
+        let namespace = topology.namespace();
 
         Ok(Self {
+            client,
             pods,
-            job_name,
+            topology,
+            namespace,
             nproc,
             nnodes,
             node_rank,
+            min_nodes: None,
+            pod_name,
+            port: PORT,
+            discovery_timeout: None,
         })
     }
 
-    fn label_selector(&self, node_ranks: &Ranks) -> String {
-        match node_ranks {
-            Ranks::Single(node_rank) => format!(
-                "{}={},{}={}",
-                NAME_LABEL, self.job_name, RANK_LABEL, node_rank,
-            ),
-            Ranks::Set(node_ranks) => {
-                let node_ranks = node_ranks.iter().map(u32::to_string).collect::<Vec<_>>();
-                format!(
-                    "{}={},{} in ({})",
-                    NAME_LABEL,
-                    self.job_name,
-                    RANK_LABEL,
-                    node_ranks.join(","),
+    /// Use `namespace` as the PMIx namespace, instead of one derived from the
+    /// workload.
+    pub fn with_namespace(mut self, namespace: String) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    /// Use `port` (and the four after it) for peer connections, instead of
+    /// [`PORT`]. Every pod in the job must use the same port.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Make [`KubernetesPeers::wait_for_peers`] fail if the pods of the world
+    /// don't all have addresses within `timeout`, explaining why each missing
+    /// pod hasn't started. Later lookups of peers wait indefinitely.
+    pub fn with_discovery_timeout(mut self, timeout: Duration) -> Self {
+        self.discovery_timeout = Some(timeout);
+        self
+    }
+
+    /// Allow the job to grow and shrink, up to `max_nodes` pods. The first
+    /// `min_nodes` pods form the initial MPI world, and each pod beyond that
+    /// runs as its own namespace.
+    pub fn with_elastic(mut self, min_nodes: u32, max_nodes: u32) -> Result<Self, Error> {
+        if !matches!(self.topology, Topology::Job { .. }) {
+            return Err(Error::UnsupportedJob(
+                "elastic mode requires a Job".to_owned(),
+            ));
+        }
+        if self.nnodes < min_nodes {
+            return Err(Error::UnsupportedJob(format!(
+                "elastic job has {} pods, but needs at least {}",
+                self.nnodes, min_nodes
+            )));
+        }
+        if max_nodes < self.nnodes.max(min_nodes) {
+            return Err(Error::UnsupportedJob(format!(
+                "elastic job has {} pods and needs at least {}, but may only grow to {}",
+                self.nnodes, min_nodes, max_nodes
+            )));
+        }
+        self.min_nodes = Some(min_nodes);
+        Ok(self)
+    }
+
+    /// The name of the workload (e.g. Job) this pod is part of.
+    pub fn job_name(&self) -> &str {
+        self.topology.name()
+    }
+
+    /// The PMIx namespace this pod's processes belong to. By default, this is
+    /// unique to the workload, so re-created Jobs with the same name (or other
+    /// jobs sharing a node) don't collide.
+    pub fn namespace(&self) -> String {
+        match self.min_nodes {
+            Some(min_nodes) if self.node_rank >= min_nodes => {
+                format!("{}.{}", self.namespace, self.node_rank)
+            }
+            _ => self.namespace.clone(),
+        }
+    }
+
+    pub fn nnodes(&self) -> u32 {
+        self.nnodes
+    }
+
+    /// First node rank and number of nodes of the namespace `nspace`, if it
+    /// is one of this job's. The number of elastic worlds isn't bounded, as the
+    /// job may have grown since.
+    fn world(&self, nspace: &sys::pmix_nspace_t) -> Option<(u32, u32)> {
+        let nspace = ffi::CStr::from_bytes_until_nul(char_to_u8(nspace))
+            .ok()?
+            .to_str()
+            .ok()?;
+        if nspace == self.namespace {
+            return Some((0, self.min_nodes.unwrap_or(self.nnodes)));
+        }
+        let min_nodes = self.min_nodes?;
+        let node_rank = nspace
+            .strip_prefix(self.namespace.as_str())?
+            .strip_prefix('.')?
+            .parse()
+            .ok()?;
+        (node_rank >= min_nodes).then_some((node_rank, 1))
+    }
+
+    /// Node rank of the pod running `proc`, if it is one of this job's
+    /// processes.
+    fn proc_node_rank(&self, proc: &sys::pmix_proc_t) -> Option<u32> {
+        let (first, n) = self.world(&proc.nspace)?;
+        let node_rank = proc.rank / (self.nproc as u32);
+        (proc.rank <= sys::PMIX_RANK_VALID && node_rank < n).then_some(first + node_rank)
+    }
+
+    fn own_world(&self) -> (u32, u32) {
+        match self.min_nodes {
+            Some(min_nodes) if self.node_rank >= min_nodes => (self.node_rank, 1),
+            Some(min_nodes) => (0, min_nodes),
+            None => (0, self.nnodes),
+        }
+    }
+
+    /// Watch the number of pods in the Job, which changes when an elastic Job
+    /// is resized. Suspended Jobs are skipped, as their size may still change.
+    pub fn watch_nnodes(&self) -> impl Stream<Item = Result<u32, Error>> + use<> {
+        let jobs = Api::<Job>::default_namespaced(self.client.clone());
+        let config =
+            watcher::Config::default().fields(&format!("metadata.name={}", self.job_name()));
+        watcher::watcher(jobs, config)
+            .inspect_err(|_| counter!(telemetry::WATCH_RESTARTS, "resource" => "jobs").increment(1))
+            .applied_objects()
+            .try_filter(|job| future::ready(!suspended(job)))
+            .map_err(Error::from)
+            .and_then(|job| {
+                future::ready(
+                    job.spec
+                        .ok_or(Error::MissingField("Job:spec"))
+                        .and_then(|spec| {
+                            indexed_job_size(
+                                spec.completion_mode.as_deref(),
+                                spec.completions.map(i64::from),
+                                spec.parallelism.map(i64::from),
+                            )
+                        }),
                 )
+            })
+    }
+
+    /// Wait until every pod in this pod's MPI world has an address, returning
+    /// the number of pods. Fails after the discovery timeout, if any.
+    pub async fn wait_for_peers(&self) -> Result<u32, Error> {
+        let (first, n) = self.own_world();
+        let node_ranks = match self.min_nodes {
+            None => Ranks::All,
+            Some(_) => Ranks::Set((first..first + n).collect()),
+        };
+        let expected = (first..first + n).collect();
+        self.wait_for_pods(&node_ranks, expected, self.discovery_timeout)
+            .await?;
+        Ok(n)
+    }
+
+    /// Wait for the addresses of the pods with `expected` node ranks, selected
+    /// by `node_ranks`. After `timeout`, fails with why each missing pod
+    /// hasn't started.
+    async fn wait_for_pods(
+        &self,
+        node_ranks: &Ranks,
+        expected: HashSet<u32>,
+        timeout: Option<Duration>,
+    ) -> Result<HashMap<u32, net::IpAddr>, Error> {
+        let mut pod_ips = pin!(self.watch_pods(node_ranks));
+        let mut found = HashMap::new();
+        let wait = async {
+            while found.len() < expected.len() {
+                #[allow(
+                    clippy::unwrap_used,
+                    reason = "watcher streams automatically recover from errors"
+                )]
+                let (node_rank, pod_ip) = pod_ips.next().await.unwrap()?;
+                if expected.contains(&node_rank) {
+                    found.insert(node_rank, pod_ip);
+                }
+            }
+            Ok::<_, Error>(())
+        };
+        match timeout {
+            Some(timeout) => {
+                if let Ok(result) = time::timeout(timeout, wait).await {
+                    result?;
+                } else {
+                    let missing = expected
+                        .iter()
+                        .filter(|node_rank| !found.contains_key(node_rank))
+                        .copied()
+                        .collect();
+                    return Err(self.diagnose(missing).await);
+                }
             }
-            Ranks::All => format!("batch.kubernetes.io/job-name={}", self.job_name),
+            None => wait.await?,
         }
+        Ok(found)
+    }
+
+    /// Explain why the pods with the `missing` node ranks have no address.
+    /// This is best-effort, as the pods or their Events may not be readable.
+    async fn diagnose(&self, missing: BTreeSet<u32>) -> Error {
+        let selector = self
+            .topology
+            .label_selector(&Ranks::Set(missing.iter().copied().collect()));
+        let pods = match self
+            .pods
+            .list(&ListParams::default().labels(&selector))
+            .await
+        {
+            Ok(pods) => pods.items,
+            Err(err) => {
+                warn!(%err, "unable to list pods");
+                Vec::new()
+            }
+        };
+        let events = Api::<Event>::default_namespaced(self.client.clone());
+
+        let mut problems = Vec::new();
+        for node_rank in missing {
+            // A failed pod may have been replaced, so prefer the newest
+            let pod = pods
+                .iter()
+                .filter(|pod| self.topology.node_rank(pod) == Some(node_rank))
+                .max_by(|a, b| {
+                    a.metadata
+                        .creation_timestamp
+                        .cmp(&b.metadata.creation_timestamp)
+                });
+            let Some(pod) = pod else {
+                problems.push(format!("node {}: no pod exists", node_rank));
+                continue;
+            };
+            let name = pod.metadata.name.clone().unwrap_or_default();
+            let params = ListParams::default().fields(&format!("involvedObject.name={}", name));
+            let pod_events = match events.list(&params).await {
+                Ok(events) => events.items,
+                Err(err) => {
+                    warn!(%err, pod = name, "unable to list events");
+                    Vec::new()
+                }
+            };
+            problems.push(format!(
+                "node {} (pod {}): {}",
+                node_rank,
+                name,
+                diagnose::pod_problem(pod, &pod_events)
+            ));
+        }
+        Error::DiscoveryTimeout(problems)
+    }
+
+    /// Watch for pods of the workload that have failed or been deleted, by
+    /// node rank.
+    pub fn watch_lost(&self) -> impl Stream<Item = Result<u32, Error>> {
+        let selector = self.topology.label_selector(&Ranks::All);
+        let config = watcher::Config::default().labels(&selector);
+        let watcher = watcher::watcher(self.pods.clone(), config).inspect_err(|_| {
+            counter!(telemetry::WATCH_RESTARTS, "resource" => "pods").increment(1)
+        });
+        let topology = &self.topology;
+
+        watcher.map_err(Error::from).try_filter_map(move |e| {
+            let result = match e {
+                watcher::Event::Delete(p) => topology.node_rank(&p),
+                watcher::Event::Apply(p) | watcher::Event::InitApply(p) => {
+                    let phase = p.status.as_ref().and_then(|s| s.phase.as_deref());
+                    topology.node_rank(&p).filter(|_| phase == Some("Failed"))
+                }
+                _ => None,
+            };
+            future::ready(Ok(result))
+        })
+    }
+
+    /// Recorder for Events about this pod's progress. Events are attached to
+    /// the workload, or this pod (if `POD_NAME` is set) for selector
+    /// topologies. If `annotate` is set, the pod is also annotated with its
+    /// current phase.
+    pub fn recorder(&self, annotate: bool) -> Option<EventRecorder> {
+        let namespace = self.client.default_namespace();
+        let pod = self.pod_name.as_ref().map(|name| ObjectReference {
+            api_version: Some("v1".to_owned()),
+            kind: Some("Pod".to_owned()),
+            name: Some(name.clone()),
+            namespace: Some(namespace.to_owned()),
+            ..Default::default()
+        });
+        let regarding = self.topology.object_ref(namespace).or(pod.clone())?;
+        let annotate = self
+            .pod_name
+            .clone()
+            .filter(|_| annotate)
+            .map(|name| (self.pods.clone(), name));
+        Some(EventRecorder::new(
+            self.client.clone(),
+            regarding,
+            pod,
+            annotate,
+            self.node_rank,
+        ))
+    }
+
+    /// Share `data` between all pods of the job, through a Secret named
+    /// `<job>-pmi-k8s-<suffix>` owned by the workload. Every pod offers its own
+    /// `data`, and all use whichever was stored first.
+    async fn shared_data(
+        &self,
+        suffix: &str,
+        data: BTreeMap<String, ByteString>,
+    ) -> Result<BTreeMap<String, ByteString>, Error> {
+        let secrets = Api::<Secret>::default_namespaced(self.client.clone());
+        let name = format!("{}-pmi-k8s-{}", self.job_name(), suffix);
+        let owner = self
+            .topology
+            .object_ref(self.client.default_namespace())
+            .map(|owner| OwnerReference {
+                api_version: owner.api_version.unwrap_or_default(),
+                kind: owner.kind.unwrap_or_default(),
+                name: owner.name.unwrap_or_default(),
+                uid: owner.uid.unwrap_or_default(),
+                ..Default::default()
+            });
+        let secret = Secret {
+            metadata: ObjectMeta {
+                name: Some(name.clone()),
+                owner_references: owner.map(|owner| vec![owner]),
+                ..Default::default()
+            },
+            data: Some(data),
+            ..Default::default()
+        };
+
+        let secret = match secrets.get_opt(&name).await? {
+            Some(secret) => secret,
+            None => match secrets.create(&PostParams::default(), &secret).await {
+                Ok(secret) => secret,
+                // Another pod may have created it first
+                Err(err) => secrets.get_opt(&name).await?.ok_or(err)?,
+            },
+        };
+        Ok(secret.data.unwrap_or_default())
+    }
+
+    /// Share a TLS CA between all pods of the job, through a Secret owned by
+    /// the workload. Every pod offers its own `ca`, and all use whichever was
+    /// stored first.
+    pub async fn shared_ca(&self, ca: tls::Ca) -> Result<tls::Ca, Error> {
+        const CERT: &str = "ca.crt";
+        const KEY: &str = "ca.key";
+
+        let data = BTreeMap::from([
+            (CERT.to_owned(), ByteString(ca.cert_pem().into())),
+            (KEY.to_owned(), ByteString(ca.key_pem().into())),
+        ]);
+        let mut data = self.shared_data("ca", data).await?;
+        let mut pem = |key: &str| {
+            data.remove(key)
+                .map(|ByteString(pem)| String::from_utf8_lossy(&pem).into_owned())
+                .ok_or(Error::MissingField("Secret:data"))
+        };
+        Ok(tls::Ca::from_pem(pem(CERT)?, pem(KEY)?))
+    }
+
+    /// Share a secret for authenticating peers between all pods of the job,
+    /// like [`KubernetesPeers::shared_ca`].
+    pub async fn shared_secret(&self, secret: Vec<u8>) -> Result<Vec<u8>, Error> {
+        const SECRET: &str = "secret";
+
+        let data = BTreeMap::from([(SECRET.to_owned(), ByteString(secret))]);
+        let mut data = self.shared_data("secret", data).await?;
+        data.remove(SECRET)
+            .map(|ByteString(secret)| secret)
+            .filter(|secret| !secret.is_empty())
+            .ok_or(Error::MissingField("Secret:data"))
+    }
+
+    pub fn hostname(&self) -> String {
+        self.topology.hostname(self.node_rank)
     }
 
     fn watch_pods(
         &self,
         node_ranks: &Ranks,
     ) -> impl futures::Stream<Item = watcher::Result<(u32, net::IpAddr)>> {
-        let config = watcher::Config::default().labels(&self.label_selector(node_ranks));
-        let watcher = watcher::watcher(self.pods.clone(), config);
-
-        watcher.try_filter_map(async |e| match e {
-            watcher::Event::Apply(p) | watcher::Event::InitApply(p) => {
-                let ip = p.status.and_then(|s| s.pod_ip).map(|ip| {
-                    ip.parse::<net::IpAddr>()
-                        .expect("pod had invalid IP address")
-                });
-                let node_rank = p.metadata.labels.and_then(|l| {
-                    l.get(RANK_LABEL)
-                        .map(|rank| rank.parse::<u32>().expect("pod had invalid rank label"))
-                });
-                Ok(node_rank.zip(ip))
-            }
-            _ => Ok(None),
+        let selector = self.topology.label_selector(node_ranks);
+        let config = watcher::Config::default().labels(&selector);
+        let watcher = watcher::watcher(self.pods.clone(), config).inspect_err(|_| {
+            counter!(telemetry::WATCH_RESTARTS, "resource" => "pods").increment(1)
+        });
+        let topology = &self.topology;
+
+        watcher.try_filter_map(move |e| {
+            let result = match e {
+                watcher::Event::Apply(p) | watcher::Event::InitApply(p) => {
+                    let node_rank = topology
+                        .node_rank(&p)
+                        .filter(|node_rank| node_ranks.contains(*node_rank));
+                    let ip = p.status.and_then(|s| s.pod_ip).map(|ip| {
+                        ip.parse::<net::IpAddr>()
+                            .expect("pod had invalid IP address")
+                    });
+                    node_rank.zip(ip)
+                }
+                _ => None,
+            };
+            future::ready(Ok(result))
         })
     }
 
-    fn port(endpoint: Endpoint) -> u16 {
+    /// The port `endpoint` listens on, on every pod.
+    pub fn port(&self, endpoint: Endpoint) -> u16 {
         match endpoint {
-            Endpoint::Fence => PORT,
-            Endpoint::Modex => PORT + 1,
+            Endpoint::Fence => self.port,
+            Endpoint::Modex => self.port + 1,
+            Endpoint::Credential => self.port + 2,
+            Endpoint::Handshake => self.port + 3,
+            Endpoint::Query => self.port + 4,
         }
     }
 }
@@ -140,60 +535,62 @@ impl PeerDiscovery for KubernetesPeers {
         proc: &sys::pmix_proc_t,
         endpoint: Endpoint,
     ) -> Result<net::SocketAddr, Self::Error> {
-        assert!(proc.rank <= sys::PMIX_RANK_VALID);
-
-        let node_ranks = Ranks::Single(proc.rank / (self.nproc as u32));
-        let mut pod_ips = pin!(self.watch_pods(&node_ranks));
-        #[allow(
-            clippy::unwrap_used,
-            reason = "watcher streams automatically recover from errors"
-        )]
-        let pod_ip = pod_ips.next().await.unwrap()?;
-        Ok(net::SocketAddr::new(pod_ip.1, Self::port(endpoint)))
+        let node_rank = self
+            .proc_node_rank(proc)
+            .ok_or_else(|| Error::UnknownProc(ProcDisplay(proc).to_string()))?;
+        let pod_ips = self
+            .wait_for_pods(&Ranks::Single(node_rank), HashSet::from([node_rank]), None)
+            .await?;
+        Ok(net::SocketAddr::new(
+            pod_ips[&node_rank],
+            self.port(endpoint),
+        ))
     }
 
     async fn peers(
         &self,
         procs: &[sys::pmix_proc_t],
         endpoint: Endpoint,
-    ) -> Result<Vec<net::SocketAddr>, Self::Error> {
-        let mut peers = HashMap::new();
-        let (num_addrs, node_ranks) = if let [
-            sys::pmix_proc_t {
-                rank: sys::PMIX_RANK_WILDCARD,
-                // TODO: Handle other namespaces
-                nspace: _,
-            },
-        ] = procs
-        {
-            (self.nnodes as usize, Ranks::All)
+    ) -> Result<HashMap<u32, net::SocketAddr>, Self::Error> {
+        let mut nodes = HashSet::new();
+        for proc in procs {
+            let unknown = || Error::UnknownProc(ProcDisplay(proc).to_string());
+            if proc.rank == sys::PMIX_RANK_WILDCARD {
+                let (first, n) = self.world(&proc.nspace).ok_or_else(unknown)?;
+                nodes.extend(first..first + n);
+            } else {
+                nodes.insert(self.proc_node_rank(proc).ok_or_else(unknown)?);
+            }
+        }
+        let num_addrs = nodes.len();
+        // The set of pods in an elastic job may change, so always select exactly.
+        let node_ranks = if self.min_nodes.is_none() && num_addrs == self.nnodes as usize {
+            Ranks::All
         } else {
-            let nodes = procs
-                .iter()
-                .map(|sys::pmix_proc_t { rank, nspace: _ }| rank / (self.nproc as u32))
-                .collect::<HashSet<_>>();
-            (nodes.len(), Ranks::Set(nodes))
+            Ranks::Set(nodes.clone())
         };
-        let mut pod_ips = pin!(self.watch_pods(&node_ranks));
-
-        while peers.len() < num_addrs {
-            #[allow(
-                clippy::unwrap_used,
-                reason = "watcher streams automatically recover from errors"
-            )]
-            let (rank, pod_ip) = pod_ips.next().await.unwrap()?;
-            peers.insert(rank, net::SocketAddr::new(pod_ip, Self::port(endpoint)));
-        }
+        let pod_ips = self.wait_for_pods(&node_ranks, nodes, None).await?;
+        Ok(pod_ips
+            .into_iter()
+            .map(|(node_rank, pod_ip)| {
+                (node_rank, net::SocketAddr::new(pod_ip, self.port(endpoint)))
+            })
+            .collect())
+    }
 
-        Ok(peers.into_values().collect::<Vec<_>>())
+    fn contains(&self, proc: &sys::pmix_proc_t) -> bool {
+        self.proc_node_rank(proc).is_some()
     }
 
     fn local_ranks(&self) -> impl Iterator<Item = u32> {
-        (self.node_rank * self.nproc as u32)..((self.node_rank + 1) * self.nproc as u32)
+        let (first, _) = self.own_world();
+        let node_rank = self.node_rank - first;
+        (node_rank * self.nproc as u32)..((node_rank + 1) * self.nproc as u32)
     }
 
     fn hostnames(&self) -> impl Iterator<Item = String> {
-        (0..self.nnodes).map(|rank| format!("{}-{}", self.job_name, rank))
+        let (first, n) = self.own_world();
+        (first..first + n).map(|rank| self.topology.hostname(rank))
     }
 
     fn node_rank(&self) -> u32 {
@@ -1,8 +1,11 @@
-use std::{error::Error, net};
+use std::{collections::HashMap, error::Error, net};
 
+mod diagnose;
 #[cfg(feature = "test-bins")]
 mod dir;
+pub mod events;
 pub mod k8s;
+pub mod topology;
 
 #[cfg(feature = "test-bins")]
 pub use dir::DirectoryPeers;
@@ -14,6 +17,9 @@ use crate::pmix::sys;
 pub enum Endpoint {
     Fence,
     Modex,
+    Credential,
+    Handshake,
+    Query,
 }
 
 pub trait PeerDiscovery {
@@ -24,12 +30,16 @@ pub trait PeerDiscovery {
         proc: &sys::pmix_proc_t,
         endpoint: Endpoint,
     ) -> Result<net::SocketAddr, Self::Error>;
+    /// Addresses of the nodes running `procs`, by node rank.
     async fn peers(
         &self,
         procs: &[sys::pmix_proc_t],
         endpoint: Endpoint,
-    ) -> Result<Vec<net::SocketAddr>, Self::Error>;
+    ) -> Result<HashMap<u32, net::SocketAddr>, Self::Error>;
 
+    /// Whether `proc` is one of this job's processes, so that [`Self::peer`]
+    /// can find it.
+    fn contains(&self, proc: &sys::pmix_proc_t) -> bool;
     fn local_ranks(&self) -> impl Iterator<Item = u32>;
     fn hostnames(&self) -> impl Iterator<Item = String>;
     fn node_rank(&self) -> u32;
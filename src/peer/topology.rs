@@ -0,0 +1,638 @@
+use futures::{StreamExt, TryStreamExt, future};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    pin::pin,
+};
+
+use k8s_openapi::api::{
+    apps::v1::StatefulSet,
+    batch::v1::Job,
+    core::v1::{ObjectReference, Pod},
+};
+use kube::{
+    Api, Client,
+    api::{ApiResource, DynamicObject, GroupVersionKind},
+    runtime::{WatchStreamExt, watcher},
+};
+use metrics::counter;
+use tracing::info;
+
+use crate::telemetry;
+
+use super::k8s::Error;
+
+const JOB_NAME_LABEL: &str = "batch.kubernetes.io/job-name";
+const JOB_INDEX_LABEL: &str = "batch.kubernetes.io/job-completion-index";
+const JOBSET_NAME_LABEL: &str = "jobset.sigs.k8s.io/jobset-name";
+const JOBSET_RJOB_LABEL: &str = "jobset.sigs.k8s.io/replicatedjob-name";
+const JOBSET_JOB_INDEX_LABEL: &str = "jobset.sigs.k8s.io/job-index";
+
+/// Which kind of workload the pods of the MPI world belong to.
+pub enum Source {
+    /// An indexed `batch/v1` Job, ranked by completion index. The Job is found
+    /// by the `JOB_NAME` environment variable.
+    Job,
+    /// A JobSet, where all replicated jobs together form one MPI world. The
+    /// JobSet is found by this pod's labels.
+    JobSet,
+    /// A StatefulSet, ranked by pod ordinal. The StatefulSet is found by this
+    /// pod's owner.
+    StatefulSet,
+    /// Arbitrary pods matching a label selector, ranked by an index label.
+    /// `name` identifies the job, as there is no workload object to name it.
+    Selector {
+        name: String,
+        selector: String,
+        index_label: String,
+        nnodes: u32,
+    },
+}
+
+pub(super) enum Ranks {
+    Single(u32),
+    Set(HashSet<u32>),
+    All,
+}
+
+impl Ranks {
+    pub(super) fn contains(&self, node_rank: u32) -> bool {
+        match self {
+            Ranks::Single(r) => *r == node_rank,
+            Ranks::Set(rs) => rs.contains(&node_rank),
+            Ranks::All => true,
+        }
+    }
+}
+
+pub(super) struct ReplicatedJob {
+    name: String,
+    replicas: u32,
+    size: u32,
+}
+
+/// The resolved layout of the MPI world: how to select its pods, and how to
+/// map a pod to its node rank.
+pub(super) enum Topology {
+    Job {
+        name: String,
+        uid: String,
+        nnodes: u32,
+    },
+    JobSet {
+        name: String,
+        uid: String,
+        replicated_jobs: Vec<ReplicatedJob>,
+        nnodes: u32,
+    },
+    StatefulSet {
+        name: String,
+        uid: String,
+        selector: String,
+        nnodes: u32,
+    },
+    Selector {
+        namespace: String,
+        /// `namespace`, made safe to use in Kubernetes resource names.
+        name: String,
+        selector: String,
+        index_label: String,
+        nnodes: u32,
+    },
+}
+
+/// Make `s` a valid DNS label (as used in most Kubernetes resource names),
+/// with room for suffixes such as `-pmi-k8s-ca`.
+fn dns_label(s: &str) -> String {
+    const MAX_LEN: usize = 40;
+    let mut label = String::with_capacity(s.len());
+    for c in s.chars() {
+        let c = if c.is_ascii_alphanumeric() {
+            c.to_ascii_lowercase()
+        } else {
+            '-'
+        };
+        if !(c == '-' && (label.is_empty() || label.ends_with('-'))) {
+            label.push(c);
+        }
+    }
+    label.truncate(MAX_LEN);
+    let label = label.trim_end_matches('-');
+    if label.is_empty() {
+        "pmi-k8s".to_owned()
+    } else {
+        label.to_owned()
+    }
+}
+
+fn label<'a>(pod: &'a Pod, key: &str) -> Option<&'a str> {
+    pod.metadata.labels.as_ref()?.get(key).map(String::as_str)
+}
+
+fn label_selector(labels: &BTreeMap<String, String>) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn rank_selector(key: &str, node_ranks: &Ranks) -> Option<String> {
+    match node_ranks {
+        Ranks::Single(node_rank) => Some(format!("{}={}", key, node_rank)),
+        Ranks::Set(node_ranks) => {
+            let node_ranks = node_ranks.iter().map(u32::to_string).collect::<Vec<_>>();
+            Some(format!("{} in ({})", key, node_ranks.join(",")))
+        }
+        Ranks::All => None,
+    }
+}
+
+/// Number of pods in an indexed Job. All pods must run concurrently to form the
+/// MPI world, so `parallelism` must match `completions`.
+pub(super) fn indexed_job_size(
+    completion_mode: Option<&str>,
+    completions: Option<i64>,
+    parallelism: Option<i64>,
+) -> Result<u32, Error> {
+    if completion_mode != Some("Indexed") {
+        return Err(Error::UnsupportedJob(
+            "completionMode must be Indexed".to_owned(),
+        ));
+    }
+    let completions =
+        completions.ok_or_else(|| Error::UnsupportedJob("completions must be set".to_owned()))?;
+    // Parallelism defaults to 1
+    let parallelism = parallelism.unwrap_or(1);
+    if parallelism != completions {
+        return Err(Error::UnsupportedJob(format!(
+            "parallelism ({}) must equal completions ({})",
+            parallelism, completions
+        )));
+    }
+    u32::try_from(completions)
+        .ok()
+        .filter(|n| *n > 0)
+        .ok_or_else(|| Error::UnsupportedJob(format!("invalid completions ({})", completions)))
+}
+
+/// Number of pods in a JobSet, i.e. in all of its replicated jobs.
+fn jobset_size(replicated_jobs: &[ReplicatedJob]) -> Result<u32, Error> {
+    replicated_jobs
+        .iter()
+        .try_fold(0u32, |n, r| r.replicas.checked_mul(r.size)?.checked_add(n))
+        .ok_or_else(|| Error::UnsupportedJob("JobSet has too many pods".to_owned()))
+}
+
+/// Whether `job` is suspended, e.g. by Kueue until it is admitted. Its spec may
+/// still change before it is resumed.
+pub(super) fn suspended(job: &Job) -> bool {
+    job.spec.as_ref().and_then(|s| s.suspend).unwrap_or(false)
+}
+
+/// Wait until the Job `name` is no longer suspended, returning it as resumed.
+async fn resumed(jobs: Api<Job>, name: &str) -> Result<Job, Error> {
+    let config = watcher::Config::default().fields(&format!("metadata.name={}", name));
+    let mut resumed = pin!(
+        watcher::watcher(jobs, config)
+            .inspect_err(|_| counter!(telemetry::WATCH_RESTARTS, "resource" => "jobs").increment(1))
+            .applied_objects()
+            .try_filter(|job| future::ready(!suspended(job)))
+    );
+    #[allow(
+        clippy::unwrap_used,
+        reason = "watcher streams automatically recover from errors"
+    )]
+    let job = resumed.next().await.unwrap()?;
+    Ok(job)
+}
+
+impl Topology {
+    /// Look up the workload. `pod` is this pod, and is required for all sources
+    /// except `Job`.
+    pub(super) async fn resolve(
+        source: Source,
+        client: Client,
+        pod: Option<&Pod>,
+    ) -> Result<Self, Error> {
+        let owner = |kind: &str| {
+            pod.and_then(|p| p.metadata.owner_references.as_ref())
+                .and_then(|refs| refs.iter().find(|r| r.kind == kind))
+                .map(|r| r.name.clone())
+        };
+
+        match source {
+            Source::Job => {
+                let name = env::var("JOB_NAME")?;
+                let jobs = Api::<Job>::default_namespaced(client);
+                let mut job = jobs.get(&name).await?;
+                if suspended(&job) {
+                    info!(job = name, "Job is suspended, waiting for it to be resumed");
+                    job = resumed(jobs, &name).await?;
+                }
+                let uid = job
+                    .metadata
+                    .uid
+                    .ok_or(Error::MissingField("Job:metadata.uid"))?;
+                let spec = job.spec.ok_or(Error::MissingField("Job:spec"))?;
+                let nnodes = indexed_job_size(
+                    spec.completion_mode.as_deref(),
+                    spec.completions.map(i64::from),
+                    spec.parallelism.map(i64::from),
+                )?;
+                Ok(Self::Job { name, uid, nnodes })
+            }
+            Source::JobSet => {
+                let name = pod
+                    .and_then(|p| label(p, JOBSET_NAME_LABEL))
+                    .ok_or(Error::MissingField("Pod:metadata.labels.jobset-name"))?
+                    .to_owned();
+                let gvk = GroupVersionKind::gvk("jobset.x-k8s.io", "v1alpha2", "JobSet");
+                let resource = ApiResource::from_gvk(&gvk);
+                let jobset = Api::<DynamicObject>::default_namespaced_with(client, &resource)
+                    .get(&name)
+                    .await?;
+                let uid = jobset
+                    .metadata
+                    .uid
+                    .ok_or(Error::MissingField("JobSet:metadata.uid"))?;
+                let replicated_jobs = jobset.data["spec"]["replicatedJobs"]
+                    .as_array()
+                    .ok_or(Error::MissingField("JobSet:spec.replicatedJobs"))?
+                    .iter()
+                    .map(|rjob| {
+                        let name = rjob["name"]
+                            .as_str()
+                            .ok_or(Error::MissingField("JobSet:spec.replicatedJobs.name"))?;
+                        let replicas = rjob["replicas"].as_u64().unwrap_or(1);
+                        let replicas = u32::try_from(replicas).map_err(|_| {
+                            Error::UnsupportedJob(format!("invalid replicas ({})", replicas))
+                        })?;
+                        let spec = &rjob["template"]["spec"];
+                        // JobSet defaults its jobs to indexed mode
+                        let size = indexed_job_size(
+                            spec["completionMode"].as_str().or(Some("Indexed")),
+                            spec["completions"].as_i64().or(Some(1)),
+                            spec["parallelism"].as_i64(),
+                        )?;
+                        Ok(ReplicatedJob {
+                            name: name.to_owned(),
+                            replicas,
+                            size,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                let nnodes = jobset_size(&replicated_jobs)?;
+                Ok(Self::JobSet {
+                    name,
+                    uid,
+                    replicated_jobs,
+                    nnodes,
+                })
+            }
+            Source::StatefulSet => {
+                let name = owner("StatefulSet")
+                    .ok_or(Error::MissingField("Pod:metadata.ownerReferences"))?;
+                let sts = Api::<StatefulSet>::default_namespaced(client)
+                    .get(&name)
+                    .await?;
+                let uid = sts
+                    .metadata
+                    .uid
+                    .ok_or(Error::MissingField("StatefulSet:metadata.uid"))?;
+                let spec = sts.spec.ok_or(Error::MissingField("StatefulSet:spec"))?;
+                let selector = spec
+                    .selector
+                    .match_labels
+                    .as_ref()
+                    .map(label_selector)
+                    .ok_or(Error::MissingField("StatefulSet:spec.selector.matchLabels"))?;
+                let replicas = spec.replicas.unwrap_or(1);
+                let nnodes = u32::try_from(replicas).map_err(|_| {
+                    Error::UnsupportedJob(format!("invalid replicas ({})", replicas))
+                })?;
+                Ok(Self::StatefulSet {
+                    name,
+                    uid,
+                    selector,
+                    nnodes,
+                })
+            }
+            Source::Selector {
+                name,
+                selector,
+                index_label,
+                nnodes,
+            } => Ok(Self::Selector {
+                name: dns_label(&name),
+                namespace: name,
+                selector,
+                index_label,
+                nnodes,
+            }),
+        }
+    }
+
+    pub(super) fn nnodes(&self) -> u32 {
+        match self {
+            Self::Job { nnodes, .. }
+            | Self::JobSet { nnodes, .. }
+            | Self::StatefulSet { nnodes, .. }
+            | Self::Selector { nnodes, .. } => *nnodes,
+        }
+    }
+
+    /// A name for this MPI world that is unique among concurrent jobs.
+    pub(super) fn namespace(&self) -> String {
+        match self {
+            Self::Job { name, uid, .. }
+            | Self::JobSet { name, uid, .. }
+            | Self::StatefulSet { name, uid, .. } => format!("{}-{}", name, uid),
+            Self::Selector { namespace, .. } => namespace.clone(),
+        }
+    }
+
+    pub(super) fn name(&self) -> &str {
+        match self {
+            Self::Job { name, .. }
+            | Self::JobSet { name, .. }
+            | Self::StatefulSet { name, .. }
+            | Self::Selector { name, .. } => name,
+        }
+    }
+
+    /// Reference to the workload object, in the Kubernetes namespace
+    /// `namespace`. Selector topologies have no single owning object.
+    pub(super) fn object_ref(&self, namespace: &str) -> Option<ObjectReference> {
+        let (api_version, kind, name, uid) = match self {
+            Self::Job { name, uid, .. } => ("batch/v1", "Job", name, uid),
+            Self::JobSet { name, uid, .. } => ("jobset.x-k8s.io/v1alpha2", "JobSet", name, uid),
+            Self::StatefulSet { name, uid, .. } => ("apps/v1", "StatefulSet", name, uid),
+            Self::Selector { .. } => return None,
+        };
+        Some(ObjectReference {
+            api_version: Some(api_version.to_owned()),
+            kind: Some(kind.to_owned()),
+            name: Some(name.clone()),
+            namespace: Some(namespace.to_owned()),
+            uid: Some(uid.clone()),
+            ..Default::default()
+        })
+    }
+
+    /// Label selector for (at least) the pods with the given node ranks. Pods
+    /// must still be filtered with `node_rank`, as not all topologies can
+    /// select individual ranks by label.
+    pub(super) fn label_selector(&self, node_ranks: &Ranks) -> String {
+        let (base, ranks) = match self {
+            Self::Job { name, .. } => (
+                format!("{}={}", JOB_NAME_LABEL, name),
+                rank_selector(JOB_INDEX_LABEL, node_ranks),
+            ),
+            Self::JobSet { name, .. } => (format!("{}={}", JOBSET_NAME_LABEL, name), None),
+            Self::StatefulSet { selector, .. } => (selector.clone(), None),
+            Self::Selector {
+                selector,
+                index_label,
+                ..
+            } => (selector.clone(), rank_selector(index_label, node_ranks)),
+        };
+        match ranks {
+            Some(ranks) => format!("{},{}", base, ranks),
+            None => base,
+        }
+    }
+
+    pub(super) fn node_rank(&self, pod: &Pod) -> Option<u32> {
+        match self {
+            Self::Job { .. } => label(pod, JOB_INDEX_LABEL)?.parse().ok(),
+            Self::JobSet {
+                replicated_jobs, ..
+            } => {
+                let rjob = label(pod, JOBSET_RJOB_LABEL)?;
+                let job_index = label(pod, JOBSET_JOB_INDEX_LABEL)?.parse::<u32>().ok()?;
+                let completion_index = label(pod, JOB_INDEX_LABEL)?.parse::<u32>().ok()?;
+
+                let mut offset = 0u32;
+                for r in replicated_jobs {
+                    if r.name == rjob {
+                        if job_index >= r.replicas || completion_index >= r.size {
+                            return None;
+                        }
+                        return offset
+                            .checked_add(job_index.checked_mul(r.size)?)?
+                            .checked_add(completion_index);
+                    }
+                    offset = offset.checked_add(r.replicas.checked_mul(r.size)?)?;
+                }
+                None
+            }
+            Self::StatefulSet { name, .. } => pod
+                .metadata
+                .name
+                .as_ref()?
+                .strip_prefix(name.as_str())?
+                .strip_prefix('-')?
+                .parse()
+                .ok(),
+            Self::Selector { index_label, .. } => label(pod, index_label)?.parse().ok(),
+        }
+    }
+
+    /// Hostname of the pod with the given node rank. These match the pod
+    /// hostnames where Kubernetes assigns them predictably.
+    pub(super) fn hostname(&self, node_rank: u32) -> String {
+        match self {
+            Self::Job { name, .. } | Self::StatefulSet { name, .. } => {
+                format!("{}-{}", name, node_rank)
+            }
+            Self::JobSet {
+                name,
+                replicated_jobs,
+                ..
+            } => {
+                let mut offset = 0;
+                for r in replicated_jobs {
+                    // Can't overflow, as `jobset_size` has been checked
+                    let rjob_size = r.replicas * r.size;
+                    if node_rank < offset + rjob_size {
+                        let job_index = (node_rank - offset) / r.size;
+                        let completion_index = (node_rank - offset) % r.size;
+                        return format!("{}-{}-{}-{}", name, r.name, job_index, completion_index);
+                    }
+                    offset += rjob_size;
+                }
+                format!("{}-{}", name, node_rank)
+            }
+            Self::Selector { .. } => format!("node-{}", node_rank),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    use super::*;
+
+    fn pod(name: &str, labels: &[(&str, &str)]) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_owned()),
+                labels: Some(
+                    labels
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_indexed_job_size() {
+        assert_eq!(
+            indexed_job_size(Some("Indexed"), Some(4), Some(4)).unwrap(),
+            4
+        );
+        assert_eq!(indexed_job_size(Some("Indexed"), Some(1), None).unwrap(), 1);
+        assert!(matches!(
+            indexed_job_size(Some("Indexed"), Some(8), Some(4)),
+            Err(Error::UnsupportedJob(_))
+        ));
+        assert!(matches!(
+            indexed_job_size(Some("NonIndexed"), Some(4), Some(4)),
+            Err(Error::UnsupportedJob(_))
+        ));
+        assert!(matches!(
+            indexed_job_size(Some("Indexed"), None, Some(4)),
+            Err(Error::UnsupportedJob(_))
+        ));
+        assert!(matches!(
+            indexed_job_size(Some("Indexed"), Some(0), Some(0)),
+            Err(Error::UnsupportedJob(_))
+        ));
+    }
+
+    #[test]
+    fn test_jobset() {
+        let replicated_jobs = vec![
+            ReplicatedJob {
+                name: "driver".to_owned(),
+                replicas: 1,
+                size: 1,
+            },
+            ReplicatedJob {
+                name: "workers".to_owned(),
+                replicas: 2,
+                size: 3,
+            },
+        ];
+        let topology = Topology::JobSet {
+            name: "js".to_owned(),
+            uid: "uid".to_owned(),
+            nnodes: jobset_size(&replicated_jobs).unwrap(),
+            replicated_jobs,
+        };
+        assert_eq!(topology.nnodes(), 7);
+
+        let p = pod(
+            "js-workers-1-2-abcde",
+            &[
+                (JOBSET_RJOB_LABEL, "workers"),
+                (JOBSET_JOB_INDEX_LABEL, "1"),
+                (JOB_INDEX_LABEL, "2"),
+            ],
+        );
+        assert_eq!(topology.node_rank(&p), Some(6));
+        assert_eq!(topology.hostname(6), "js-workers-1-2");
+
+        let p = pod(
+            "js-driver-0-0-abcde",
+            &[
+                (JOBSET_RJOB_LABEL, "driver"),
+                (JOBSET_JOB_INDEX_LABEL, "0"),
+                (JOB_INDEX_LABEL, "0"),
+            ],
+        );
+        assert_eq!(topology.node_rank(&p), Some(0));
+        assert_eq!(topology.hostname(0), "js-driver-0-0");
+
+        // Indices beyond the replicated job's size belong to no node
+        let p = pod(
+            "js-workers-2-0-abcde",
+            &[
+                (JOBSET_RJOB_LABEL, "workers"),
+                (JOBSET_JOB_INDEX_LABEL, "2"),
+                (JOB_INDEX_LABEL, "0"),
+            ],
+        );
+        assert_eq!(topology.node_rank(&p), None);
+        let p = pod(
+            "js-workers-0-3-abcde",
+            &[
+                (JOBSET_RJOB_LABEL, "workers"),
+                (JOBSET_JOB_INDEX_LABEL, "0"),
+                (JOB_INDEX_LABEL, "3"),
+            ],
+        );
+        assert_eq!(topology.node_rank(&p), None);
+    }
+
+    #[test]
+    fn test_jobset_size_overflow() {
+        let replicated_jobs = vec![ReplicatedJob {
+            name: "workers".to_owned(),
+            replicas: u32::MAX,
+            size: 2,
+        }];
+        assert!(matches!(
+            jobset_size(&replicated_jobs),
+            Err(Error::UnsupportedJob(_))
+        ));
+    }
+
+    #[test]
+    fn test_statefulset() {
+        let topology = Topology::StatefulSet {
+            name: "mpi".to_owned(),
+            uid: "uid".to_owned(),
+            selector: "app=mpi".to_owned(),
+            nnodes: 4,
+        };
+        assert_eq!(topology.node_rank(&pod("mpi-3", &[])), Some(3));
+        assert_eq!(topology.node_rank(&pod("other-3", &[])), None);
+        assert_eq!(topology.label_selector(&Ranks::Single(3)), "app=mpi");
+    }
+
+    #[test]
+    fn test_selector() {
+        let topology = Topology::Selector {
+            namespace: "My Job".to_owned(),
+            name: "my-job".to_owned(),
+            selector: "app=mpi".to_owned(),
+            index_label: "index".to_owned(),
+            nnodes: 4,
+        };
+        assert_eq!(topology.node_rank(&pod("x", &[("index", "2")])), Some(2));
+        assert_eq!(
+            topology.label_selector(&Ranks::Single(2)),
+            "app=mpi,index=2"
+        );
+        assert_eq!(topology.label_selector(&Ranks::All), "app=mpi");
+        assert_eq!(topology.namespace(), "My Job");
+        assert_eq!(topology.name(), "my-job");
+    }
+
+    #[test]
+    fn test_dns_label() {
+        assert_eq!(dns_label("my-job"), "my-job");
+        assert_eq!(dns_label("App=MPI,tier=x"), "app-mpi-tier-x");
+        assert_eq!(dns_label("--job.1--"), "job-1");
+        assert_eq!(dns_label("!!!"), "pmi-k8s");
+        assert_eq!(dns_label(&"a".repeat(100)).len(), 40);
+    }
+}
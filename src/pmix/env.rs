@@ -1,6 +1,5 @@
 use super::sys;
-use std::{ffi, fmt::Debug, io, marker::PhantomData, os::unix::ffi::OsStrExt};
-use tokio::{fs, io::AsyncWriteExt};
+use std::{ffi, fmt::Debug, marker::PhantomData, os::unix::ffi::OsStrExt};
 
 #[cfg(test)]
 use std::ptr;
@@ -20,20 +19,6 @@ impl EnvVars {
         self.into_iter()
     }
 
-    pub async fn write(&self, file: &mut fs::File) -> Result<(), io::Error> {
-        for (k, v) in self {
-            let line = format!(
-                "{}='{}'\n",
-                k.to_str().expect("Invalid env key generated by PMIx"),
-                v.to_str()
-                    .expect("Invalid env value generated by PMIx")
-                    .replace("'", r"'\''"),
-            );
-            file.write_all(line.as_bytes()).await?;
-        }
-        Ok(())
-    }
-
     #[cfg(test)]
     fn new(vars: &[ffi::CString]) -> Self {
         let mut ptr = ptr::null_mut();
@@ -1,13 +1,23 @@
-#[cfg(test)]
-use std::ptr;
-
-use std::{ffi, ops::Deref, slice, sync::RwLock};
+use std::{
+    ffi,
+    ops::Deref,
+    ptr, slice,
+    sync::{
+        RwLock,
+        atomic::{AtomicU32, Ordering},
+    },
+};
 use tokio::sync::mpsc;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
-use crate::pmix::{char_to_u8, u8_to_char};
+use crate::pmix::{char_to_u8, nspace_from_str, u8_to_char};
 
-use super::{slice_from_raw_parts, sys, value::PmixError};
+use super::{
+    ProcDisplay,
+    info::{self, Key},
+    slice_from_raw_parts, sys,
+    value::{self, PmixError},
+};
 
 pub struct ModexCallback(sys::pmix_modex_cbfunc_t, *mut ffi::c_void);
 
@@ -71,6 +81,72 @@ unsafe extern "C" fn test_cbfunc(
     }
 }
 
+pub struct CredentialCallback(sys::pmix_credential_cbfunc_t, *mut ffi::c_void);
+
+// SAFETY: A single-use callback + data.
+unsafe impl Send for CredentialCallback {}
+
+impl CredentialCallback {
+    pub fn call(self, status: sys::pmix_status_t, mut credential: Vec<u8>) {
+        let Some(cbfunc) = self.0 else {
+            return;
+        };
+
+        let mut bo = sys::pmix_byte_object_t {
+            bytes: credential.as_mut_ptr() as *mut ffi::c_char,
+            size: credential.len(),
+        };
+        // SAFETY: libpmix copies the credential before the callback returns,
+        // so `bo` need only live until then.
+        unsafe { cbfunc(status, &mut bo, ptr::null_mut(), 0, self.1) }
+    }
+}
+
+pub struct ValidationCallback(sys::pmix_validation_cbfunc_t, *mut ffi::c_void);
+
+// SAFETY: A single-use callback + data.
+unsafe impl Send for ValidationCallback {}
+
+impl ValidationCallback {
+    pub fn call(self, status: sys::pmix_status_t) {
+        let Some(cbfunc) = self.0 else {
+            return;
+        };
+
+        // SAFETY: No data is passed to libpmix.
+        unsafe { cbfunc(status, ptr::null_mut(), 0, self.1) }
+    }
+}
+
+pub struct QueryCallback(sys::pmix_info_cbfunc_t, *mut ffi::c_void);
+
+// SAFETY: A single-use callback + data.
+unsafe impl Send for QueryCallback {}
+
+impl QueryCallback {
+    pub fn call(self, status: sys::pmix_status_t, infos: Vec<sys::pmix_info_t>) {
+        let Some(cbfunc) = self.0 else {
+            return;
+        };
+
+        let mut infos = Box::new(infos);
+        let (ptr, len) = (infos.as_mut_ptr(), infos.len());
+
+        // SAFETY: `ptr` lives as long as `infos`, which is freed by libpmix
+        // using `release_vec_info`.
+        unsafe {
+            cbfunc(
+                status,
+                ptr,
+                len,
+                self.1,
+                Some(release_vec_info),
+                Box::into_raw(infos) as *mut ffi::c_void,
+            )
+        }
+    }
+}
+
 pub struct CData(*mut ffi::c_char, usize);
 
 // SAFETY: Just a bunch of (read-only) bytes.
@@ -133,11 +209,83 @@ pub struct DirectModexEvent {
     pub cb: ModexCallback,
 }
 
+pub struct AbortEvent {
+    pub proc: sys::pmix_proc_t,
+    pub status: ffi::c_int,
+    pub message: String,
+}
+
+pub enum CredentialEvent {
+    /// `proc` requested a credential for itself.
+    Get {
+        proc: sys::pmix_proc_t,
+        cb: CredentialCallback,
+    },
+    /// `proc` asked whether `credential` is valid.
+    Validate {
+        proc: sys::pmix_proc_t,
+        credential: Vec<u8>,
+        cb: ValidationCallback,
+    },
+}
+
+/// A query key we know how to answer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryKey {
+    /// `PMIX_QUERY_NAMESPACES`: the namespaces known to this server.
+    Namespaces,
+    /// `PMIX_QUERY_PROC_TABLE`: every process in a namespace, on all nodes.
+    ProcTable,
+    /// `PMIX_QUERY_LOCAL_PROC_TABLE`: the processes in a namespace on this
+    /// node.
+    LocalProcTable,
+    Unsupported(String),
+}
+
+impl QueryKey {
+    fn new(key: &ffi::CStr) -> Self {
+        if key == info::QueryNamespaces::KEY {
+            Self::Namespaces
+        } else if key == info::QueryProcTable::KEY {
+            Self::ProcTable
+        } else if key == info::QueryLocalProcTable::KEY {
+            Self::LocalProcTable
+        } else {
+            Self::Unsupported(key.to_string_lossy().into_owned())
+        }
+    }
+}
+
+pub struct Query {
+    pub keys: Vec<QueryKey>,
+    /// The namespace the query is about, from the `PMIX_NSPACE` qualifier.
+    pub nspace: Option<String>,
+}
+
+pub struct QueryEvent {
+    pub requester: sys::pmix_proc_t,
+    pub queries: Vec<Query>,
+    pub cb: QueryCallback,
+}
+
+pub enum ClientEvent {
+    /// `proc` connected to the server.
+    Connected { proc: sys::pmix_proc_t },
+    /// `proc` called `PMIx_Finalize`.
+    Finalized { proc: sys::pmix_proc_t },
+    /// `proc` disconnected without calling `PMIx_Finalize`.
+    Lost { proc: sys::pmix_proc_t },
+}
+
 pub enum State {
     Client,
     Server {
         fence_tx: mpsc::UnboundedSender<FenceEvent>,
         modex_tx: mpsc::UnboundedSender<DirectModexEvent>,
+        abort_tx: mpsc::UnboundedSender<AbortEvent>,
+        credential_tx: mpsc::UnboundedSender<CredentialEvent>,
+        client_tx: mpsc::UnboundedSender<ClientEvent>,
+        query_tx: mpsc::UnboundedSender<QueryEvent>,
     },
 }
 
@@ -160,23 +308,154 @@ pub unsafe extern "C" fn release_vec_u8(cbdata: *mut ffi::c_void) {
     drop(data)
 }
 
+/// # Safety
+///
+/// `cbdata` must be a pointer created from `Box<Vec<sys::pmix_info_t>>::into_raw()`
+pub unsafe extern "C" fn release_vec_info(cbdata: *mut ffi::c_void) {
+    // SAFETY: The inverse of the creation of `cbdata`
+    let infos = unsafe { Box::from_raw(cbdata as *mut Vec<sys::pmix_info_t>) };
+    drop(infos)
+}
+
 /* For callbacks, one must either:
  * 1. Return PMIX_OPERATION_SUCCEEDED
  * 2. Call return PMIX_SUCCESS, then call cbfunc(PMIX_SUCCESS, cbdata)
  */
 
+/// Report a change in a client's lifecycle, if anything is listening. Clients
+/// are never refused because of this.
+fn send_client_event(event: ClientEvent) {
+    #[allow(clippy::unwrap_used, reason = "no asserts poison the global state")]
+    let guard = PMIX_STATE.read().unwrap();
+
+    if let Some(State::Server { ref client_tx, .. }) = *guard {
+        // Nothing may be tracking clients
+        client_tx.send(event).unwrap_or_default();
+    }
+}
+
 unsafe extern "C" fn client_connected(
-    _proc: *const sys::pmix_proc_t,
-    _server_object: *mut std::ffi::c_void,
+    proc: *const sys::pmix_proc_t,
+    _server_object: *mut ffi::c_void,
     _info: *mut sys::pmix_info_t,
     ninfo: usize,
     _cbfunc: sys::pmix_op_cbfunc_t,
-    _cbdata: *mut std::ffi::c_void,
+    _cbdata: *mut ffi::c_void,
 ) -> sys::pmix_status_t {
-    info!("client_connected2 called, ninfo: {}", ninfo);
+    // SAFETY: `proc` is passed to us by libpmix, assume it is valid.
+    let proc = unsafe { *proc };
+    info!(rank = proc.rank, ninfo, "client connected");
+    send_client_event(ClientEvent::Connected { proc });
     sys::PMIX_OPERATION_SUCCEEDED as sys::pmix_status_t
 }
 
+unsafe extern "C" fn client_finalized(
+    proc: *const sys::pmix_proc_t,
+    _server_object: *mut ffi::c_void,
+    _cbfunc: sys::pmix_op_cbfunc_t,
+    _cbdata: *mut ffi::c_void,
+) -> sys::pmix_status_t {
+    // SAFETY: `proc` is passed to us by libpmix, assume it is valid.
+    let proc = unsafe { *proc };
+    debug!(rank = proc.rank, "client finalized");
+    send_client_event(ClientEvent::Finalized { proc });
+    sys::PMIX_OPERATION_SUCCEEDED as sys::pmix_status_t
+}
+
+/// Event handler for clients whose connection was lost before they finalized,
+/// registered by the server for [`CLIENT_LOST_EVENTS`].
+///
+/// # Safety
+///
+/// Must only be called by libpmix, as a `pmix_notification_fn_t`.
+pub unsafe extern "C" fn client_lost(
+    _evhdlr_registration_id: usize,
+    status: sys::pmix_status_t,
+    source: *const sys::pmix_proc_t,
+    _info: *mut sys::pmix_info_t,
+    _ninfo: usize,
+    _results: *mut sys::pmix_info_t,
+    _nresults: usize,
+    cbfunc: sys::pmix_event_notification_cbfunc_fn_t,
+    cbdata: *mut ffi::c_void,
+) {
+    if !source.is_null() {
+        // SAFETY: `source` is passed to us by libpmix, and not NULL.
+        let proc = unsafe { *source };
+        warn!(
+            rank = proc.rank,
+            status, "client disconnected without finalizing"
+        );
+        send_client_event(ClientEvent::Lost { proc });
+    }
+    if let Some(cbfunc) = cbfunc {
+        // SAFETY: `cbfunc` and `cbdata` were passed to us by libpmix, and we
+        // have no results to return.
+        unsafe {
+            cbfunc(
+                sys::PMIX_EVENT_ACTION_COMPLETE as sys::pmix_status_t,
+                ptr::null_mut(),
+                0,
+                None,
+                ptr::null_mut(),
+                cbdata,
+            )
+        };
+    }
+}
+
+/// Events raised by libpmix when a client's connection drops before it calls
+/// `PMIx_Finalize`.
+pub const CLIENT_LOST_EVENTS: [sys::pmix_status_t; 2] = [
+    sys::PMIX_ERR_PROC_TERM_WO_SYNC,
+    sys::PMIX_ERR_LOST_CONNECTION,
+];
+
+unsafe extern "C" fn abort(
+    proc: *const sys::pmix_proc_t,
+    _server_object: *mut ffi::c_void,
+    status: ffi::c_int,
+    msg: *const ffi::c_char,
+    _procs: *mut sys::pmix_proc_t,
+    _nprocs: usize,
+    _cbfunc: sys::pmix_op_cbfunc_t,
+    _cbdata: *mut ffi::c_void,
+) -> sys::pmix_status_t {
+    // SAFETY: `proc` is passed to us by libpmix, assume it is valid.
+    let proc = unsafe { *proc };
+    let message = if msg.is_null() {
+        String::new()
+    } else {
+        // SAFETY: `msg` is a C string passed to us by libpmix, and not NULL.
+        unsafe { ffi::CStr::from_ptr(msg) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    warn!(rank = proc.rank, status, %message, "client aborted");
+
+    #[allow(clippy::unwrap_used, reason = "no asserts poison the global state")]
+    let guard = PMIX_STATE.read().unwrap();
+
+    // The server fails on any abort, which terminates every local rank
+    // (whatever `procs` asks for), and with it the rest of the job.
+    if let Some(State::Server { ref abort_tx, .. }) = *guard {
+        let event = AbortEvent {
+            proc,
+            status,
+            message,
+        };
+        match abort_tx.send(event) {
+            Ok(()) => sys::PMIX_OPERATION_SUCCEEDED as sys::pmix_status_t,
+            Err(err) => {
+                warn!(%err, "error queueing abort");
+                sys::PMIX_ERROR
+            }
+        }
+    } else {
+        sys::PMIX_ERR_INIT as sys::pmix_status_t
+    }
+}
+
 unsafe extern "C" fn fence_nb(
     procs: *const sys::pmix_proc_t,
     nprocs: usize,
@@ -185,7 +464,7 @@ unsafe extern "C" fn fence_nb(
     data: *mut std::ffi::c_char,
     ndata: usize,
     cbfunc: sys::pmix_modex_cbfunc_t,
-    cbdata: *mut std::ffi::c_void,
+    cbdata: *mut ffi::c_void,
 ) -> sys::pmix_status_t {
     // SAFETY: According to the standard, we (the host) are responsible for
     // free'ing the data passed to `fence_nb`.
@@ -234,7 +513,7 @@ unsafe extern "C" fn direct_modex(
     info: *const sys::pmix_info_t,
     ninfo: usize,
     cbfunc: sys::pmix_modex_cbfunc_t,
-    cbdata: *mut std::ffi::c_void,
+    cbdata: *mut ffi::c_void,
 ) -> sys::pmix_status_t {
     // SAFETY: `info` is provided by `libpmix`, and is valid for this function.
     let info = unsafe { slice_from_raw_parts(info, ninfo) };
@@ -272,12 +551,73 @@ unsafe extern "C" fn direct_modex(
     }
 }
 
+/// Queue a credential request, if anything is serving them.
+fn send_credential_event(event: CredentialEvent) -> sys::pmix_status_t {
+    #[allow(clippy::unwrap_used, reason = "no asserts poison the global state")]
+    let guard = PMIX_STATE.read().unwrap();
+
+    if let Some(State::Server {
+        ref credential_tx, ..
+    }) = *guard
+    {
+        match credential_tx.send(event) {
+            Ok(()) => sys::PMIX_SUCCESS as sys::pmix_status_t,
+            // Nothing is serving credentials
+            Err(_) => sys::PMIX_ERR_NOT_SUPPORTED,
+        }
+    } else {
+        sys::PMIX_ERR_INIT as sys::pmix_status_t
+    }
+}
+
+unsafe extern "C" fn get_credential(
+    proc: *const sys::pmix_proc_t,
+    _directives: *const sys::pmix_info_t,
+    _ndirs: usize,
+    cbfunc: sys::pmix_credential_cbfunc_t,
+    cbdata: *mut ffi::c_void,
+) -> sys::pmix_status_t {
+    info!("get_credential called");
+    // SAFETY: `proc` is passed to us by libpmix, assume it is valid.
+    let proc = unsafe { *proc };
+    let cb = CredentialCallback(cbfunc, cbdata);
+    send_credential_event(CredentialEvent::Get { proc, cb })
+}
+
+unsafe extern "C" fn validate_credential(
+    proc: *const sys::pmix_proc_t,
+    cred: *const sys::pmix_byte_object_t,
+    _directives: *const sys::pmix_info_t,
+    _ndirs: usize,
+    cbfunc: sys::pmix_validation_cbfunc_t,
+    cbdata: *mut ffi::c_void,
+) -> sys::pmix_status_t {
+    info!("validate_credential called");
+    if cred.is_null() {
+        return sys::PMIX_ERR_BAD_PARAM;
+    }
+    // SAFETY: `proc` is passed to us by libpmix, assume it is valid.
+    let proc = unsafe { *proc };
+    // SAFETY: `cred` is passed to us by libpmix, and not NULL. The bytes are
+    // only valid for this call, so are copied.
+    let credential = unsafe {
+        let cred = &*cred;
+        char_to_u8(slice_from_raw_parts(cred.bytes, cred.size)).to_vec()
+    };
+    let cb = ValidationCallback(cbfunc, cbdata);
+    send_credential_event(CredentialEvent::Validate {
+        proc,
+        credential,
+        cb,
+    })
+}
+
 unsafe extern "C" fn publish(
     _proc_: *const sys::pmix_proc_t,
     _info: *const sys::pmix_info_t,
     _ninfo: usize,
     _cbfunc: sys::pmix_op_cbfunc_t,
-    _cbdata: *mut std::ffi::c_void,
+    _cbdata: *mut ffi::c_void,
 ) -> sys::pmix_status_t {
     info!("publish called");
     sys::PMIX_ERR_NOT_SUPPORTED as sys::pmix_status_t
@@ -289,28 +629,123 @@ unsafe extern "C" fn lookup(
     _info: *const sys::pmix_info_t,
     _ninfo: usize,
     _cbfunc: sys::pmix_lookup_cbfunc_t,
-    _cbdata: *mut std::ffi::c_void,
+    _cbdata: *mut ffi::c_void,
 ) -> sys::pmix_status_t {
     info!("lookup called");
     sys::PMIX_ERR_NOT_SUPPORTED as sys::pmix_status_t
 }
 
+/// Parse a query from libpmix, keeping the keys we don't support so they can
+/// be reported.
+///
+/// # Safety
+///
+/// `query` must be passed to us by libpmix: `keys` is a `NULL`-terminated
+/// array of C strings (or `NULL`), and `qualifiers` has `nqual` elements.
+unsafe fn parse_query(query: &sys::pmix_query_t) -> Query {
+    let mut keys = Vec::new();
+    let mut key = query.keys;
+    // SAFETY: We stop at the terminating `NULL`, which is never passed.
+    while !key.is_null() && !unsafe { *key }.is_null() {
+        // SAFETY: `*key` is a valid C string, and not NULL.
+        keys.push(QueryKey::new(unsafe { ffi::CStr::from_ptr(*key) }));
+        // SAFETY: `key` is not the terminating `NULL`, so there is another.
+        key = unsafe { key.add(1) };
+    }
+    // SAFETY: Satisfied by this function's safety requirements.
+    let qualifiers = unsafe { slice_from_raw_parts(query.qualifiers, query.nqual) };
+    let nspace = info::Nspace::find(qualifiers).map(|ns| ns.to_string_lossy().into_owned());
+    Query { keys, nspace }
+}
+
 unsafe extern "C" fn query(
-    _proct: *mut sys::pmix_proc_t,
-    _queries: *mut sys::pmix_query_t,
-    _nqueries: usize,
-    _cbfunc: sys::pmix_info_cbfunc_t,
-    _cbdata: *mut std::ffi::c_void,
+    proct: *mut sys::pmix_proc_t,
+    queries: *mut sys::pmix_query_t,
+    nqueries: usize,
+    cbfunc: sys::pmix_info_cbfunc_t,
+    cbdata: *mut ffi::c_void,
 ) -> sys::pmix_status_t {
-    info!("query called");
-    sys::PMIX_ERR_NOT_SUPPORTED as sys::pmix_status_t
+    if proct.is_null() {
+        return sys::PMIX_ERR_BAD_PARAM;
+    }
+    // SAFETY: `proct` is passed to us by libpmix, and not NULL.
+    let requester = unsafe { *proct };
+    // SAFETY: `queries` is provided by `libpmix`, and is valid for this
+    // function.
+    let queries = unsafe { slice_from_raw_parts(queries, nqueries) }
+        .iter()
+        // SAFETY: Each query is passed to us by libpmix.
+        .map(|query| unsafe { parse_query(query) })
+        .collect::<Vec<_>>();
+    info!(rank = requester.rank, nqueries, "query called");
+    let supported = queries
+        .iter()
+        .flat_map(|query| &query.keys)
+        .any(|key| !matches!(key, QueryKey::Unsupported(_)));
+    if !supported {
+        return sys::PMIX_ERR_NOT_SUPPORTED;
+    }
+
+    #[allow(clippy::unwrap_used, reason = "no asserts poison the global state")]
+    let guard = PMIX_STATE.read().unwrap();
+
+    if let Some(State::Server { ref query_tx, .. }) = *guard {
+        let cb = QueryCallback(cbfunc, cbdata);
+        match query_tx.send(QueryEvent {
+            requester,
+            queries,
+            cb,
+        }) {
+            Ok(()) => sys::PMIX_SUCCESS as sys::pmix_status_t,
+            // Nothing is serving queries
+            Err(_) => sys::PMIX_ERR_NOT_SUPPORTED,
+        }
+    } else {
+        sys::PMIX_ERR_INIT as sys::pmix_status_t
+    }
+}
+
+/// Tools (such as debuggers) connecting are given the identity they ask for,
+/// or a namespace of their own.
+unsafe extern "C" fn tool_connected(
+    info: *mut sys::pmix_info_t,
+    ninfo: usize,
+    cbfunc: sys::pmix_tool_connection_cbfunc_t,
+    cbdata: *mut ffi::c_void,
+) {
+    static TOOLS: AtomicU32 = AtomicU32::new(0);
+
+    // SAFETY: `info` is provided by `libpmix`, and is valid for this function.
+    let info = unsafe { slice_from_raw_parts(info, ninfo) };
+    let requested = info::Nspace::find(info).map(ffi::CStr::to_string_lossy);
+    let nspace = match &requested {
+        Some(nspace) => nspace_from_str(nspace),
+        None => nspace_from_str(&format!(
+            "pmi-k8s.tool.{}.{}",
+            std::process::id(),
+            TOOLS.fetch_add(1, Ordering::Relaxed)
+        )),
+    };
+    let rank = info::Rank::find(info).map_or(0, |value::Rank(rank)| *rank);
+    let mut proc = sys::pmix_proc_t { nspace, rank };
+    info!(
+        tool = %ProcDisplay(&proc),
+        requested = requested.is_some(),
+        "tool connected"
+    );
+
+    if let Some(cbfunc) = cbfunc {
+        // SAFETY: `cbfunc` and `cbdata` were passed to us by libpmix, which
+        // copies `proc` before returning.
+        unsafe { cbfunc(sys::PMIX_SUCCESS as sys::pmix_status_t, &mut proc, cbdata) }
+    }
 }
 
 pub fn server_module() -> sys::pmix_server_module_t {
     sys::pmix_server_module_t {
         client_connected: None, // DEPRECATED
-        client_finalized: None,
-        abort: None,
+        client_finalized: Some(client_finalized),
+        abort: Some(abort),
         fence_nb: Some(fence_nb),
         direct_modex: Some(direct_modex),
         publish: Some(publish),
@@ -325,14 +760,14 @@ pub fn server_module() -> sys::pmix_server_module_t {
         /* v2x interfaces */
         notify_event: None,
         query: Some(query),
-        tool_connected: None, // DEPRECATED
-        log: None,            // DEPRECATED
+        tool_connected: Some(tool_connected),
+        log: None, // DEPRECATED
         allocate: None,
         job_control: None,
         monitor: None,
         /* v3x interfaces */
-        get_credential: None,
-        validate_credential: None,
+        get_credential: Some(get_credential),
+        validate_credential: Some(validate_credential),
         iof_pull: None,
         push_stdin: None,
         /* v4x interfaces */
@@ -1,7 +1,7 @@
 use std::ffi;
 use std::mem::MaybeUninit;
 
-use crate::pmix::value::DataPtr;
+use crate::pmix::{char_to_u8, value::DataPtr};
 
 use super::sys;
 use super::value::{self, Element, PmixStatus, Tagged};
@@ -31,6 +31,17 @@ pub unsafe trait Key {
         // SAFETY: initialized with `K::store`, and return code checked
         unsafe { v.assume_init() }
     }
+
+    /// The value of the first info in `infos` with this key, if it has the
+    /// expected type.
+    fn find(infos: &[sys::pmix_info_t]) -> Option<&Self::Value> {
+        let info = infos.iter().find(|info| {
+            ffi::CStr::from_bytes_until_nul(char_to_u8(&info.key)).ok() == Some(Self::KEY)
+        })?;
+        Self::Value::tag_matches(&info.value).ok()?;
+        // SAFETY: We have just checked the tag
+        Some(unsafe { Self::Value::load(&info.value) })
+    }
 }
 
 // SAFETY: Info elements are valid arrays, and this is type-erased so we don't
@@ -39,6 +50,12 @@ unsafe impl Element for sys::pmix_info_t {
     const ELEM_TAG: sys::pmix_data_type_t = sys::PMIX_INFO as _;
 }
 
+// SAFETY: Process info elements are valid arrays, and libpmix copies the
+// strings they point to when loading them.
+unsafe impl Element for sys::pmix_proc_info_t {
+    const ELEM_TAG: sys::pmix_data_type_t = sys::PMIX_PROC_INFO as _;
+}
+
 macro_rules! pmix_info_key_from {
     ($S:ident, $T:ty, $tag:expr) => {
         pub struct $S();
@@ -59,6 +76,13 @@ pmix_info_key_from!(NumNodes, u32, sys::PMIX_NUM_NODES);
 pmix_info_key_from!(JobInfo, bool, sys::PMIX_JOB_INFO);
 pmix_info_key_from!(JobInfoArray, [sys::pmix_info_t], sys::PMIX_JOB_INFO_ARRAY);
 pmix_info_key_from!(JobId, ffi::CStr, sys::PMIX_JOBID);
+pmix_info_key_from!(JobNumApps, u32, sys::PMIX_JOB_NUM_APPS);
+
+pmix_info_key_from!(AppInfo, [sys::pmix_info_t], sys::PMIX_APP_INFO_ARRAY);
+pmix_info_key_from!(AppNum, u32, sys::PMIX_APPNUM);
+pmix_info_key_from!(AppSize, u32, sys::PMIX_APP_SIZE);
+pmix_info_key_from!(AppLeader, value::Rank, sys::PMIX_APPLDR);
+pmix_info_key_from!(AppArgv, ffi::CStr, sys::PMIX_APP_ARGV);
 
 pmix_info_key_from!(JobSize, u32, sys::PMIX_JOB_SIZE);
 pmix_info_key_from!(MaxProcs, u32, sys::PMIX_MAX_PROCS);
@@ -75,6 +99,21 @@ pmix_info_key_from!(LocalSize, u32, sys::PMIX_LOCAL_SIZE);
 pmix_info_key_from!(ServerTmpdir, ffi::CStr, sys::PMIX_SERVER_TMPDIR);
 pmix_info_key_from!(SystemTmpdir, ffi::CStr, sys::PMIX_SYSTEM_TMPDIR);
 pmix_info_key_from!(ServerSystemSupport, bool, sys::PMIX_SERVER_SYSTEM_SUPPORT);
+pmix_info_key_from!(ServerToolSupport, bool, sys::PMIX_SERVER_TOOL_SUPPORT);
+pmix_info_key_from!(RendezvousFile, ffi::CStr, sys::PMIX_LAUNCHER_RNDZ_FILE);
+pmix_info_key_from!(Nspace, ffi::CStr, sys::PMIX_NSPACE);
+
+pmix_info_key_from!(QueryNamespaces, ffi::CStr, sys::PMIX_QUERY_NAMESPACES);
+pmix_info_key_from!(
+    QueryProcTable,
+    [sys::pmix_proc_info_t],
+    sys::PMIX_QUERY_PROC_TABLE
+);
+pmix_info_key_from!(
+    QueryLocalProcTable,
+    [sys::pmix_proc_info_t],
+    sys::PMIX_QUERY_LOCAL_PROC_TABLE
+);
 
 #[cfg(test)]
 mod test {
@@ -142,6 +181,22 @@ mod test {
         assert_eq!(unsafe { u32::load(&infos[0].value) }, &7);
     }
 
+    #[test]
+    fn test_find() {
+        let infos = [NodeId::info(&7), JobSize::info(&42), Nspace::info(c"job")];
+        assert_eq!(JobSize::find(&infos), Some(&42));
+        assert_eq!(Nspace::find(&infos), Some(c"job"));
+        assert_eq!(UniverseSize::find(&infos), None);
+
+        // The key matches, but the type doesn't
+        pub struct TestScalarKey();
+        unsafe impl Key for TestScalarKey {
+            const KEY: &ffi::CStr = TestKey::KEY;
+            type Value = u32;
+        }
+        assert_eq!(TestScalarKey::find(&[TestKey::info(&[1])]), None);
+    }
+
     #[test]
     fn test_tag_mismatch() {
         let value = into_value(JobSize::info(&42));
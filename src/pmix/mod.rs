@@ -1,4 +1,4 @@
-use std::{ffi, slice};
+use std::{ffi, fmt, slice};
 
 #[cfg(feature = "test-bins")]
 pub mod client;
@@ -22,6 +22,30 @@ pub fn is_initialized() -> bool {
     status != 0
 }
 
+/// Formats a process as `namespace:rank`, for logging.
+pub struct ProcDisplay<'a>(pub &'a sys::pmix_proc_t);
+
+impl fmt::Display for ProcDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nspace = ffi::CStr::from_bytes_until_nul(char_to_u8(&self.0.nspace))
+            .map(ffi::CStr::to_string_lossy)
+            .unwrap_or_default();
+        match self.0.rank {
+            sys::PMIX_RANK_WILDCARD => write!(f, "{}:*", nspace),
+            rank => write!(f, "{}:{}", nspace, rank),
+        }
+    }
+}
+
+/// A namespace as its fixed-size C representation, truncated if too long.
+pub fn nspace_from_str(namespace: &str) -> sys::pmix_nspace_t {
+    let mut nspace: sys::pmix_nspace_t = [0; _];
+    let namespace = namespace.as_bytes();
+    let len = namespace.len().min(nspace.len() - 1);
+    nspace[..len].copy_from_slice(u8_to_char(&namespace[..len]));
+    nspace
+}
+
 pub fn char_to_u8(chars: &[ffi::c_char]) -> &[u8] {
     let ptr = chars.as_ptr();
     // SAFETY: This is the recommended way to transmute [ffi::c_char] to [u8]
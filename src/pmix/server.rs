@@ -16,16 +16,47 @@ use super::{
     env, globals,
     info::{self, Key},
     sys, u8_to_char,
-    value::{PmixError, PmixStatus},
+    value::{self, PmixError, PmixStatus},
 };
 
 pub struct ServerEvents<'a> {
     fence_rx: mpsc::UnboundedReceiver<globals::FenceEvent>,
     modex_rx: mpsc::UnboundedReceiver<globals::DirectModexEvent>,
+    abort_rx: Option<mpsc::UnboundedReceiver<globals::AbortEvent>>,
+    credential_rx: Option<mpsc::UnboundedReceiver<globals::CredentialEvent>>,
+    client_rx: Option<mpsc::UnboundedReceiver<globals::ClientEvent>>,
+    query_rx: Option<mpsc::UnboundedReceiver<globals::QueryEvent>>,
     _server: &'a PhantomData<Server<'a>>,
 }
 
 impl<'a> ServerEvents<'a> {
+    /// Clients that called `PMIx_Abort`. May only be called once.
+    #[allow(clippy::unwrap_used, reason = "documented to only be called once")]
+    pub fn aborts(&mut self) -> mpsc::UnboundedReceiver<globals::AbortEvent> {
+        self.abort_rx.take().unwrap()
+    }
+
+    /// Requests to issue and validate credentials. May only be called once.
+    /// If never called, clients are told credentials are not supported.
+    #[allow(clippy::unwrap_used, reason = "documented to only be called once")]
+    pub fn credentials(&mut self) -> mpsc::UnboundedReceiver<globals::CredentialEvent> {
+        self.credential_rx.take().unwrap()
+    }
+
+    /// Clients connecting, finalizing and disconnecting. May only be called
+    /// once.
+    #[allow(clippy::unwrap_used, reason = "documented to only be called once")]
+    pub fn clients(&mut self) -> mpsc::UnboundedReceiver<globals::ClientEvent> {
+        self.client_rx.take().unwrap()
+    }
+
+    /// Queries from clients and tools, e.g. for the job's process table. May
+    /// only be called once. If never called, queries are not supported.
+    #[allow(clippy::unwrap_used, reason = "documented to only be called once")]
+    pub fn queries(&mut self) -> mpsc::UnboundedReceiver<globals::QueryEvent> {
+        self.query_rx.take().unwrap()
+    }
+
     pub async fn run<D: PeerDiscovery>(
         self,
         fence: fence::NetFence<'a, D>,
@@ -42,19 +73,29 @@ pub struct Server<'a> {
 }
 
 impl<'a> Server<'a> {
+    /// Start the PMIx server, with its sockets in `dirname`. Tools (such as
+    /// debuggers) may connect, and find the server from `rendezvous`, if given.
     pub fn init(
         dirname: &'a Path,
         hostname: &ffi::OsStr,
+        rendezvous: Option<&Path>,
     ) -> Result<(Self, ServerEvents<'a>), globals::InitError> {
         let dirname =
             ffi::CString::new(dirname.as_os_str().as_encoded_bytes()).expect("invalid file path");
         let hostname = ffi::CString::new(hostname.as_bytes()).expect("invalid hostname");
-        let infos: [sys::pmix_info_t; _] = [
+        let rendezvous = rendezvous.map(|path| {
+            ffi::CString::new(path.as_os_str().as_encoded_bytes()).expect("invalid file path")
+        });
+        let mut infos = vec![
             info::ServerTmpdir::info(&dirname),
             info::SystemTmpdir::info(&dirname),
             info::ServerSystemSupport::info(&true),
+            info::ServerToolSupport::info(&true),
             info::Hostname::info(&hostname),
         ];
+        if let Some(rendezvous) = &rendezvous {
+            infos.push(info::RendezvousFile::info(rendezvous));
+        }
         let mut module = globals::server_module();
 
         #[allow(clippy::unwrap_used, reason = "no asserts poison the global state")]
@@ -65,19 +106,56 @@ impl<'a> Server<'a> {
         }
         let (fence_tx, fence_rx) = mpsc::unbounded_channel();
         let (modex_tx, modex_rx) = mpsc::unbounded_channel();
-        *guard = Some(globals::State::Server { fence_tx, modex_tx });
+        let (abort_tx, abort_rx) = mpsc::unbounded_channel();
+        let (credential_tx, credential_rx) = mpsc::unbounded_channel();
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+        let (query_tx, query_rx) = mpsc::unbounded_channel();
+        *guard = Some(globals::State::Server {
+            fence_tx,
+            modex_tx,
+            abort_tx,
+            credential_tx,
+            client_tx,
+            query_tx,
+        });
         // SAFETY: global state accessed by the function pointers in `module` is
         // populated. `infos` is a pointer to an info array of length `ninfo`.
         PmixStatus(unsafe {
             sys::PMIx_server_init(&mut module, infos.as_ptr() as *mut _, infos.len())
         })
         .check()?;
+        // Finalize the server if anything below fails
+        drop(guard);
+        let server = Self { _dir: &PhantomData };
+
+        let codes = globals::CLIENT_LOST_EVENTS;
+        // SAFETY: `codes` is an array of length `ncodes`. Without a callback,
+        // registration completes before returning, and returns the handler's
+        // reference (or a negative error).
+        let id = unsafe {
+            sys::PMIx_Register_event_handler(
+                codes.as_ptr() as *mut _,
+                codes.len(),
+                ptr::null_mut(),
+                0,
+                Some(globals::client_lost),
+                None,
+                ptr::null_mut(),
+            )
+        };
+        if id < 0 {
+            Err(PmixError(id))?;
+        }
 
         Ok((
-            Self { _dir: &PhantomData },
+            server,
             ServerEvents {
                 fence_rx,
                 modex_rx,
+                abort_rx: Some(abort_rx),
+                credential_rx: Some(credential_rx),
+                client_rx: Some(client_rx),
+                query_rx: Some(query_rx),
                 _server: &PhantomData,
             },
         ))
@@ -99,6 +177,17 @@ impl<'a> Drop for Server<'a> {
     }
 }
 
+/// Status code of the event raised when the number of nodes in an elastic job
+/// changes. The event carries the new number of nodes as `PMIX_NUM_NODES`.
+pub const EVENT_JOB_RESIZED: sys::pmix_status_t = sys::PMIX_EXTERNAL_ERR_BASE - 1;
+
+/// An application context within a namespace, covering `size` consecutive
+/// ranks.
+pub struct App {
+    pub size: u32,
+    pub argv: Option<ffi::CString>,
+}
+
 pub struct Namespace<'a> {
     nspace: sys::pmix_nspace_t,
     server: PhantomData<&'a Server<'a>>,
@@ -109,11 +198,17 @@ impl<'a> Namespace<'a> {
     pub fn register(
         _server: &'a Server,
         namespace: &ffi::CStr,
+        job_id: &ffi::CStr,
         hostnames: &[String],
         nlocalprocs: u16,
+        apps: &[App],
+        universe_size: u32,
     ) -> Result<Self, PmixError> {
         let namespace = namespace.to_bytes_with_nul();
         let mut nspace: sys::pmix_nspace_t = [0; _];
+        if namespace.len() > nspace.len() {
+            return Err(PmixError(sys::PMIX_ERR_BAD_PARAM));
+        }
         nspace[..namespace.len()].copy_from_slice(u8_to_char(namespace));
 
         let nnodes = hostnames.len() as u32;
@@ -134,13 +229,47 @@ impl<'a> Namespace<'a> {
             .join(";");
         let proc_map = ffi::CString::from_str(&proc_map).expect("invalid proc map generated");
 
-        let mut infos = [
-            info::UniverseSize::info(&(nnodes * nlocalprocs as u32)),
-            info::JobSize::info(&(nnodes * nlocalprocs as u32)),
+        let nprocs = nnodes * nlocalprocs as u32;
+        assert_eq!(
+            apps.iter().map(|app| app.size).sum::<u32>(),
+            nprocs,
+            "apps do not cover the namespace"
+        );
+
+        let mut infos = vec![
+            info::UniverseSize::info(&universe_size),
+            info::MaxProcs::info(&universe_size),
+            info::JobSize::info(&nprocs),
             info::ProcMap::info(&proc_map),
             info::NodeMap::info(&node_map),
+            info::JobId::info(job_id),
+            info::JobNumApps::info(&(apps.len() as u32)),
         ];
 
+        let mut leader = 0;
+        for (appnum, app) in apps.iter().enumerate() {
+            let appnum = appnum as u32;
+            let mut app_infos = vec![
+                info::AppNum::info(&appnum),
+                info::AppSize::info(&app.size),
+                info::AppLeader::info(&value::Rank(leader)),
+            ];
+            if let Some(argv) = &app.argv {
+                app_infos.push(info::AppArgv::info(argv));
+            }
+            infos.push(info::AppInfo::info(&app_infos));
+
+            // Every process needs to know which app it (and its peers) belong
+            // to, this is what backs `MPI_APPNUM`.
+            infos.extend((leader..leader + app.size).map(|rank| {
+                info::ProcInfo::info(&[
+                    info::Rank::info(&value::Rank(rank)),
+                    info::AppNum::info(&appnum),
+                ])
+            }));
+            leader += app.size;
+        }
+
         // SAFETY: No significant safety concerns.
         PmixStatus(unsafe {
             sys::PMIx_server_register_nspace(
@@ -160,6 +289,34 @@ impl<'a> Namespace<'a> {
     }
 }
 
+impl<'a> Namespace<'a> {
+    /// Notify the local clients in this namespace of an event.
+    pub fn notify(
+        &self,
+        status: sys::pmix_status_t,
+        infos: &[sys::pmix_info_t],
+    ) -> Result<(), PmixError> {
+        let source = sys::pmix_proc_t {
+            nspace: self.nspace,
+            rank: sys::PMIX_RANK_WILDCARD,
+        };
+        // SAFETY: `infos` is a valid info array of length `ninfo`. Without a
+        // callback, libpmix copies the infos before returning.
+        PmixStatus(unsafe {
+            sys::PMIx_Notify_event(
+                status,
+                &source,
+                sys::PMIX_RANGE_NAMESPACE as sys::pmix_data_range_t,
+                infos.as_ptr(),
+                infos.len(),
+                None,
+                ptr::null_mut(),
+            )
+        })
+        .check()
+    }
+}
+
 impl<'a> Drop for Namespace<'a> {
     fn drop(&mut self) {
         // SAFETY: We must have called `PMIx_server_register_nspace` to acquire
@@ -240,9 +397,22 @@ mod test {
         assert!(!is_initialized());
         {
             let tempdir = TempDir::new("server").unwrap();
-            let _s = Server::init(tempdir.path(), &nix::unistd::gethostname().unwrap()).unwrap();
+            let hostname = nix::unistd::gethostname().unwrap();
+            let _s = Server::init(tempdir.path(), &hostname, None).unwrap();
             assert!(is_initialized());
         }
         assert!(!is_initialized());
     }
+
+    #[tokio::test]
+    #[serial(server)]
+    async fn test_tool_rendezvous() {
+        let tempdir = TempDir::new("server").unwrap();
+        let rendezvous = tempdir.path().join("server.uri");
+        let hostname = nix::unistd::gethostname().unwrap();
+        let _s = Server::init(tempdir.path(), &hostname, Some(&rendezvous)).unwrap();
+        // Tools connect to the URI in the file, with `PMIX_SERVER_URI=file:...`
+        let uri = std::fs::read_to_string(&rendezvous).unwrap();
+        assert!(uri.contains("tcp"), "{}", uri);
+    }
 }
@@ -0,0 +1,394 @@
+//! Answers to queries from tools attached to the PMIx server, such as parallel
+//! debuggers listing the processes of the job with `PMIX_QUERY_PROC_TABLE` to
+//! find the PIDs to attach to.
+//!
+//! Each pod only knows about its own processes, so for the job-wide process
+//! table it asks every pod of the namespace for theirs.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::os::unix::process::ExitStatusExt;
+use std::pin::pin;
+use std::process::ExitStatus;
+use std::rc::Rc;
+use std::{ffi, io, net::SocketAddr};
+
+use futures::{StreamExt, TryStreamExt, future::select, stream::FuturesUnordered};
+use serde::{Deserialize, Serialize};
+use tokio::{net, sync::mpsc};
+use tokio_stream::wrappers::{TcpListenerStream, UnboundedReceiverStream};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    ModexError,
+    net::Backoff,
+    peer::{Endpoint, PeerDiscovery},
+    pmix::{
+        ProcDisplay,
+        globals::{self, QueryEvent, QueryKey},
+        info::{self, Key},
+        nspace_from_str, sys,
+    },
+    tls::Tls,
+    wire,
+};
+
+/// A process of the job, as reported to tools.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProcEntry {
+    pub namespace: String,
+    pub rank: u32,
+    pub hostname: String,
+    pub executable: String,
+    /// The process ID in the pod, if `pmi-k8s` launched the process.
+    pub pid: Option<u32>,
+    /// Set once the process has exited, to 128 plus the signal if it was
+    /// killed.
+    pub exit_code: Option<i32>,
+}
+
+impl ProcEntry {
+    fn state(&self) -> sys::pmix_proc_state_t {
+        let state = match (self.pid, self.exit_code) {
+            (_, Some(_)) => sys::PMIX_PROC_STATE_TERMINATED,
+            (Some(_), None) => sys::PMIX_PROC_STATE_RUNNING,
+            (None, None) => sys::PMIX_PROC_STATE_UNDEF,
+        };
+        state as sys::pmix_proc_state_t
+    }
+}
+
+/// The processes of this pod, updated as they are launched and exit.
+#[derive(Default)]
+pub struct ProcTable(RefCell<BTreeMap<u32, ProcEntry>>);
+
+impl ProcTable {
+    pub fn insert(&self, entry: ProcEntry) {
+        let mut table = self.0.borrow_mut();
+        table.insert(entry.rank, entry);
+    }
+
+    pub fn started(&self, rank: u32, pid: Option<u32>) {
+        let mut table = self.0.borrow_mut();
+        if let Some(entry) = table.get_mut(&rank) {
+            entry.pid = pid;
+        }
+    }
+
+    pub fn exited(&self, rank: u32, status: ExitStatus) {
+        let code = status
+            .code()
+            .or_else(|| status.signal().map(|signal| 128 + signal));
+        let mut table = self.0.borrow_mut();
+        if let Some(entry) = table.get_mut(&rank) {
+            entry.exit_code = code;
+        }
+    }
+
+    /// The processes of `namespace`, in rank order.
+    pub fn entries(&self, namespace: &str) -> Vec<ProcEntry> {
+        let table = self.0.borrow();
+        table
+            .values()
+            .filter(|entry| entry.namespace == namespace)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Describe `entries` as a process table for libpmix, under the key `K`.
+fn proc_table<K: Key<Value = [sys::pmix_proc_info_t]>>(entries: &[ProcEntry]) -> sys::pmix_info_t {
+    let strings = entries
+        .iter()
+        .map(|entry| {
+            (
+                ffi::CString::new(entry.hostname.as_str()).unwrap_or_default(),
+                ffi::CString::new(entry.executable.as_str()).unwrap_or_default(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let procs = entries
+        .iter()
+        .zip(&strings)
+        .map(|(entry, (hostname, executable))| sys::pmix_proc_info_t {
+            proc: sys::pmix_proc_t {
+                nspace: nspace_from_str(&entry.namespace),
+                rank: entry.rank,
+            },
+            hostname: hostname.as_ptr() as *mut _,
+            executable_name: executable.as_ptr() as *mut _,
+            pid: entry.pid.unwrap_or_default() as _,
+            exit_code: entry.exit_code.unwrap_or_default(),
+            state: entry.state(),
+        })
+        .collect::<Vec<_>>();
+    // libpmix copies the strings, so they need only live until here
+    K::info(&procs)
+}
+
+pub struct NetQuery<'a, D> {
+    listener: net::TcpListener,
+    discovery: &'a D,
+    namespace: String,
+    table: Rc<ProcTable>,
+    wire: wire::Options,
+}
+
+impl<'a, D: PeerDiscovery> NetQuery<'a, D> {
+    pub async fn new(
+        addr: SocketAddr,
+        discovery: &'a D,
+        namespace: String,
+    ) -> Result<Self, ModexError<D::Error>> {
+        Ok(Self {
+            listener: net::TcpListener::bind(addr).await?,
+            discovery,
+            namespace,
+            table: Default::default(),
+            wire: Default::default(),
+        })
+    }
+
+    /// Authenticate connections to and from peers with `secret`.
+    pub fn with_secret(mut self, secret: wire::Secret) -> Self {
+        self.wire.secret = Some(secret);
+        self
+    }
+
+    /// Encrypt connections to and from peers with `tls`.
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.wire.tls = Some(tls);
+        self
+    }
+
+    /// Retry connecting to peers that aren't listening yet with `backoff`.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.wire.backoff = backoff;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        #[allow(clippy::unwrap_used, reason = "We know we have a socket bound")]
+        self.listener.local_addr().unwrap()
+    }
+
+    /// This pod's processes, to be kept up to date as they start and exit.
+    pub fn table(&self) -> Rc<ProcTable> {
+        self.table.clone()
+    }
+
+    /// Collect the processes of `namespace` from every pod.
+    #[instrument(level = "debug", name = "query_gather", skip(discovery, options))]
+    async fn gather(
+        discovery: &'a D,
+        options: &wire::Options,
+        namespace: &str,
+    ) -> Result<Vec<ProcEntry>, ModexError<D::Error>> {
+        let wildcard = sys::pmix_proc_t {
+            nspace: nspace_from_str(namespace),
+            rank: sys::PMIX_RANK_WILDCARD,
+        };
+        let addrs = discovery
+            .peers(&[wildcard], Endpoint::Query)
+            .await
+            .map_err(ModexError::Peer)?;
+        let request = serde_json::to_vec(namespace).map_err(io::Error::from)?;
+        let tables = addrs
+            .into_values()
+            .map(async |addr| {
+                let mut s = wire::connect(&addr, Endpoint::Query, options).await?;
+                wire::write_frame(&mut s, &request).await?;
+                let response = wire::read_frame(&mut s).await?;
+                debug!(%addr, "received process table");
+                let entries: Vec<ProcEntry> =
+                    serde_json::from_slice(&response).map_err(io::Error::from)?;
+                Ok::<_, ModexError<D::Error>>(entries)
+            })
+            .collect::<FuturesUnordered<_>>()
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut entries = tables.into_iter().flatten().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.rank);
+        Ok(entries)
+    }
+
+    #[instrument(
+        level = "debug",
+        name = "query_respond",
+        skip_all,
+        fields(peer = ?c.peer_addr().ok()),
+    )]
+    async fn respond(
+        c: net::TcpStream,
+        table: &ProcTable,
+        options: &wire::Options,
+    ) -> Result<(), ModexError<D::Error>> {
+        let mut c = wire::accept(c, Endpoint::Query, options).await?;
+        let request = wire::read_frame(&mut c).await?;
+        let namespace: String = serde_json::from_slice(&request).map_err(io::Error::from)?;
+        let entries = table.entries(&namespace);
+        let response = serde_json::to_vec(&entries).map_err(io::Error::from)?;
+        wire::write_frame(&mut c, &response).await?;
+        Ok(())
+    }
+
+    async fn handle(
+        discovery: &'a D,
+        namespace: &str,
+        table: &ProcTable,
+        options: &wire::Options,
+        event: QueryEvent,
+    ) {
+        let QueryEvent {
+            requester,
+            queries,
+            cb,
+        } = event;
+        let mut results = Vec::new();
+        let mut complete = true;
+        for query in queries {
+            let nspace = query.nspace.as_deref().unwrap_or(namespace);
+            for key in query.keys {
+                match key {
+                    QueryKey::Namespaces => {
+                        let namespaces = ffi::CString::new(namespace).unwrap_or_default();
+                        results.push(info::QueryNamespaces::info(&namespaces));
+                    }
+                    QueryKey::ProcTable => match Self::gather(discovery, options, nspace).await {
+                        Ok(entries) => {
+                            results.push(proc_table::<info::QueryProcTable>(&entries));
+                        }
+                        Err(err) => {
+                            warn!(%err, requester = %ProcDisplay(&requester), "gathering process table");
+                            complete = false;
+                        }
+                    },
+                    QueryKey::LocalProcTable => {
+                        let entries = table.entries(nspace);
+                        results.push(proc_table::<info::QueryLocalProcTable>(&entries));
+                    }
+                    QueryKey::Unsupported(key) => {
+                        debug!(key, requester = %ProcDisplay(&requester), "unsupported query");
+                        complete = false;
+                    }
+                }
+            }
+        }
+
+        let status = if results.is_empty() {
+            sys::PMIX_ERR_NOT_FOUND
+        } else if !complete {
+            sys::PMIX_ERR_PARTIAL_SUCCESS
+        } else {
+            sys::PMIX_SUCCESS as sys::pmix_status_t
+        };
+        cb.call(status, results);
+    }
+
+    #[instrument(name = "query", skip_all, fields(addr = %self.addr()))]
+    pub async fn serve(
+        self,
+        events: mpsc::UnboundedReceiver<globals::QueryEvent>,
+    ) -> Result<(), ModexError<D::Error>> {
+        let Self {
+            listener,
+            discovery,
+            namespace,
+            table,
+            wire,
+        } = self;
+        let requests = UnboundedReceiverStream::new(events).for_each_concurrent(8, async |e| {
+            Self::handle(discovery, &namespace, &table, &wire, e).await
+        });
+        let responses =
+            TcpListenerStream::new(listener).for_each_concurrent(8, async |c| match c {
+                Ok(c) => Self::respond(c, &table, &wire)
+                    .await
+                    .unwrap_or_else(|err| warn!(%err, "query response")),
+                Err(err) => warn!(%err, "query accept"),
+            });
+
+        // Stop once the PMIx server is finalized
+        select(pin!(requests), pin!(responses)).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::panic)]
+    use std::net::Ipv4Addr;
+
+    use futures::future::{Either, join};
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::peer::DirectoryPeers;
+
+    fn entry(rank: u32, pid: Option<u32>) -> ProcEntry {
+        ProcEntry {
+            namespace: "job".to_owned(),
+            rank,
+            hostname: format!("mpi-{}", rank / 2),
+            executable: "a.out".to_owned(),
+            pid,
+            exit_code: None,
+        }
+    }
+
+    #[test]
+    fn test_proc_table() {
+        let table = ProcTable::default();
+        table.insert(entry(1, None));
+        table.insert(entry(0, None));
+        table.insert(ProcEntry {
+            namespace: "other".to_owned(),
+            ..entry(2, None)
+        });
+        table.started(0, Some(42));
+        table.exited(1, ExitStatus::from_raw(9));
+
+        let entries = table.entries("job");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].pid, Some(42));
+        assert_eq!(entries[0].state(), sys::PMIX_PROC_STATE_RUNNING as _);
+        // Killed by SIGKILL
+        assert_eq!(entries[1].exit_code, Some(137));
+        assert_eq!(entries[1].state(), sys::PMIX_PROC_STATE_TERMINATED as _);
+    }
+
+    #[tokio::test]
+    async fn test_gather() {
+        let tmpdir = TempDir::new("query-test").unwrap();
+        let discovery = DirectoryPeers::new(tmpdir.path(), 2, 2);
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let mut queries = Vec::new();
+        for node_rank in 0..2 {
+            let query = NetQuery::new(addr, &discovery, "job".to_owned())
+                .await
+                .unwrap();
+            discovery.register(&query.addr()).unwrap();
+            for rank in node_rank * 2..(node_rank + 1) * 2 {
+                query.table().insert(entry(rank, Some(100 + rank)));
+            }
+            queries.push(query);
+        }
+
+        let (_tx0, rx0) = mpsc::unbounded_channel();
+        let (_tx1, rx1) = mpsc::unbounded_channel();
+        let second = queries.pop().unwrap();
+        let first = queries.pop().unwrap();
+        let serve = join(first.serve(rx0), second.serve(rx1));
+        let gather = NetQuery::gather(&discovery, &wire::Options::default(), "job");
+        let Either::Left((entries, _)) = select(pin!(gather), pin!(serve)).await else {
+            panic!("expected process table");
+        };
+        let entries = entries.unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.rank).collect::<Vec<_>>(),
+            [0, 1, 2, 3]
+        );
+        assert_eq!(entries[3].pid, Some(103));
+        assert_eq!(entries[3].hostname, "mpi-1");
+    }
+}
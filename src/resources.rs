@@ -0,0 +1,216 @@
+//! Detecting how many processes to run in this pod, for `--nproc=auto`.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use thiserror::Error;
+
+/// Variables listing the GPUs visible to the container, in order of
+/// preference. The NVIDIA device plugin sets `NVIDIA_VISIBLE_DEVICES`.
+pub const GPU_VARS: [&str; 2] = ["NVIDIA_VISIBLE_DEVICES", "CUDA_VISIBLE_DEVICES"];
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable to read {0}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("invalid process count in {0}: {1:?}")]
+    Invalid(PathBuf, String),
+    #[error("unable to detect the number of CPUs")]
+    Cpus(#[source] io::Error),
+}
+
+/// Where a detected process count came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File(PathBuf),
+    Gpus(&'static str),
+    CpuQuota,
+    Cpuset,
+    Cpus,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::Gpus(var) => write!(f, "GPUs in {}", var),
+            Self::CpuQuota => write!(f, "cgroup CPU quota"),
+            Self::Cpuset => write!(f, "cgroup cpuset"),
+            Self::Cpus => write!(f, "available CPUs"),
+        }
+    }
+}
+
+/// Detect the number of processes to run from, in order of preference, `file`
+/// (e.g. a downward API volume exposing `limits.cpu`), the GPUs visible to the
+/// container, or its cgroup's CPU quota and cpuset.
+pub fn detect(file: Option<&Path>) -> Result<(u16, Source), Error> {
+    detect_in(file, |var| std::env::var_os(var), Path::new(CGROUP_ROOT))
+}
+
+fn detect_in(
+    file: Option<&Path>,
+    vars: impl Fn(&str) -> Option<OsString>,
+    cgroup: &Path,
+) -> Result<(u16, Source), Error> {
+    if let Some(path) = file {
+        return Ok((read_count(path)?, Source::File(path.to_owned())));
+    }
+    for var in GPU_VARS {
+        if let Some(n) = vars(var).as_deref().and_then(count_gpus) {
+            return Ok((n, Source::Gpus(var)));
+        }
+    }
+    match (cpu_quota(cgroup), cpuset(cgroup)) {
+        (Some(quota), Some(cpus)) if quota <= cpus => Ok((quota, Source::CpuQuota)),
+        (_, Some(cpus)) => Ok((cpus, Source::Cpuset)),
+        (Some(quota), None) => Ok((quota, Source::CpuQuota)),
+        (None, None) => {
+            let cpus = thread::available_parallelism().map_err(Error::Cpus)?;
+            Ok((saturate(cpus.get() as u64), Source::Cpus))
+        }
+    }
+}
+
+fn saturate(n: u64) -> u16 {
+    u16::try_from(n).unwrap_or(u16::MAX)
+}
+
+fn read_count(path: &Path) -> Result<u16, Error> {
+    let contents = fs::read_to_string(path).map_err(|err| Error::Read(path.to_owned(), err))?;
+    let contents = contents.trim();
+    contents
+        .parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| Error::Invalid(path.to_owned(), contents.to_owned()))
+}
+
+/// Count the devices in a list such as `0,1` or `GPU-<uuid>,GPU-<uuid>`.
+fn count_gpus(devices: &OsStr) -> Option<u16> {
+    let devices = devices.to_str()?;
+    if matches!(devices, "all" | "none" | "void" | "NoDevFiles") {
+        return None;
+    }
+    let n = devices.split(',').filter(|d| !d.trim().is_empty()).count();
+    (n > 0).then(|| saturate(n as u64))
+}
+
+/// The CPU quota, rounded up to whole CPUs, from cgroup v2 or v1.
+fn cpu_quota(cgroup: &Path) -> Option<u16> {
+    let (quota, period) = match fs::read_to_string(cgroup.join("cpu.max")) {
+        Ok(max) => {
+            let (quota, period) = max.trim().split_once(' ')?;
+            (quota.parse::<u64>().ok()?, period.parse::<u64>().ok()?)
+        }
+        Err(_) => {
+            let read = |file: &str| fs::read_to_string(cgroup.join("cpu").join(file)).ok();
+            // An unlimited quota is -1, which fails to parse
+            let quota = read("cpu.cfs_quota_us")?.trim().parse::<u64>().ok()?;
+            (
+                quota,
+                read("cpu.cfs_period_us")?.trim().parse::<u64>().ok()?,
+            )
+        }
+    };
+    (quota > 0 && period > 0).then(|| saturate(quota.div_ceil(period)))
+}
+
+/// The number of CPUs in the cpuset, from cgroup v2 or v1.
+fn cpuset(cgroup: &Path) -> Option<u16> {
+    let cpus = fs::read_to_string(cgroup.join("cpuset.cpus.effective"))
+        .or_else(|_| fs::read_to_string(cgroup.join("cpuset").join("cpuset.effective_cpus")))
+        .ok()?;
+    let mut n = 0;
+    for range in cpus.trim().split(',').filter(|r| !r.is_empty()) {
+        n += match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (first.parse::<u64>().ok()?, last.parse::<u64>().ok()?);
+                (last + 1).checked_sub(first)?
+            }
+            None => range.parse::<u64>().map(|_| 1).ok()?,
+        };
+    }
+    (n > 0).then(|| saturate(n))
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    fn no_vars(_: &str) -> Option<OsString> {
+        None
+    }
+
+    #[test]
+    fn test_detect() {
+        let tmpdir = tempdir::TempDir::new("resources-test").unwrap();
+        let cgroup = tmpdir.path();
+
+        // Falls back to the host's CPUs without a cgroup
+        let (_, source) = detect_in(None, no_vars, cgroup).unwrap();
+        assert_eq!(source, Source::Cpus);
+
+        fs::write(cgroup.join("cpuset.cpus.effective"), "0-3,8\n").unwrap();
+        assert_eq!(
+            detect_in(None, no_vars, cgroup).unwrap(),
+            (5, Source::Cpuset)
+        );
+        fs::write(cgroup.join("cpu.max"), "max 100000\n").unwrap();
+        assert_eq!(
+            detect_in(None, no_vars, cgroup).unwrap(),
+            (5, Source::Cpuset)
+        );
+        fs::write(cgroup.join("cpu.max"), "250000 100000\n").unwrap();
+        assert_eq!(
+            detect_in(None, no_vars, cgroup).unwrap(),
+            (3, Source::CpuQuota)
+        );
+
+        let gpus = |var: &str| -> Option<OsString> {
+            (var == "NVIDIA_VISIBLE_DEVICES").then(|| "GPU-a,GPU-b".into())
+        };
+        assert_eq!(
+            detect_in(None, gpus, cgroup).unwrap(),
+            (2, Source::Gpus("NVIDIA_VISIBLE_DEVICES"))
+        );
+        let all = |var: &str| -> Option<OsString> {
+            (var == "NVIDIA_VISIBLE_DEVICES").then(|| "all".into())
+        };
+        assert_eq!(detect_in(None, all, cgroup).unwrap(), (3, Source::CpuQuota));
+
+        let file = cgroup.join("limits.cpu");
+        fs::write(&file, "4\n").unwrap();
+        assert_eq!(
+            detect_in(Some(&file), gpus, cgroup).unwrap(),
+            (4, Source::File(file.clone()))
+        );
+        fs::write(&file, "0\n").unwrap();
+        assert!(matches!(
+            detect_in(Some(&file), gpus, cgroup),
+            Err(Error::Invalid(..))
+        ));
+    }
+
+    #[test]
+    fn test_cgroup_v1() {
+        let tmpdir = tempdir::TempDir::new("resources-test").unwrap();
+        let cgroup = tmpdir.path();
+        fs::create_dir(cgroup.join("cpu")).unwrap();
+        fs::write(cgroup.join("cpu/cpu.cfs_quota_us"), "-1\n").unwrap();
+        fs::write(cgroup.join("cpu/cpu.cfs_period_us"), "100000\n").unwrap();
+        assert_eq!(cpu_quota(cgroup), None);
+        fs::write(cgroup.join("cpu/cpu.cfs_quota_us"), "200000\n").unwrap();
+        assert_eq!(cpu_quota(cgroup), Some(2));
+
+        fs::create_dir(cgroup.join("cpuset")).unwrap();
+        fs::write(cgroup.join("cpuset/cpuset.effective_cpus"), "2,4-5\n").unwrap();
+        assert_eq!(cpuset(cgroup), Some(3));
+    }
+}
@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+
+use futures::future;
+use metrics::{Unit, describe_counter, describe_histogram};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder};
+use tokio::sync::broadcast;
+use tracing_subscriber::{
+    EnvFilter,
+    filter::{LevelFilter, ParseError},
+};
+
+use crate::LogFormat;
+
+pub const FENCES_STARTED: &str = "pmi_k8s_fences_started_total";
+pub const FENCES_COMPLETED: &str = "pmi_k8s_fences_completed_total";
+pub const FENCES_FAILED: &str = "pmi_k8s_fences_failed_total";
+pub const FENCE_DURATION: &str = "pmi_k8s_fence_duration_seconds";
+pub const FENCE_PEER_WAIT: &str = "pmi_k8s_fence_peer_wait_seconds";
+pub const FENCE_BYTES: &str = "pmi_k8s_fence_bytes";
+pub const DMODEX_ISSUED: &str = "pmi_k8s_dmodex_issued_total";
+pub const DMODEX_SERVED: &str = "pmi_k8s_dmodex_served_total";
+pub const DMODEX_DURATION: &str = "pmi_k8s_dmodex_request_duration_seconds";
+pub const CREDENTIALS_ISSUED: &str = "pmi_k8s_credentials_issued_total";
+pub const CREDENTIALS_VALIDATED: &str = "pmi_k8s_credentials_validated_total";
+pub const CONNECT_RETRIES: &str = "pmi_k8s_peer_connect_retries_total";
+pub const WATCH_RESTARTS: &str = "pmi_k8s_watch_restarts_total";
+
+const SECONDS_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0,
+];
+const BYTES_BUCKETS: &[f64] = &[1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9];
+
+/// Serve Prometheus metrics over HTTP on `addr`. Without this, metrics are
+/// recorded into a no-op recorder.
+pub fn install_metrics(addr: SocketAddr) -> Result<(), BuildError> {
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_owned()), SECONDS_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Suffix("_bytes".to_owned()), BYTES_BUCKETS)?
+        .install()?;
+
+    describe_counter!(FENCES_STARTED, "Fences started by local processes");
+    describe_counter!(FENCES_COMPLETED, "Fences completed");
+    describe_counter!(FENCES_FAILED, "Fences failed or abandoned");
+    describe_histogram!(
+        FENCE_DURATION,
+        Unit::Seconds,
+        "Time from a fence starting locally until it completes"
+    );
+    describe_histogram!(
+        FENCE_PEER_WAIT,
+        Unit::Seconds,
+        "Time from a fence starting locally until each peer's data arrives"
+    );
+    describe_histogram!(
+        FENCE_BYTES,
+        Unit::Bytes,
+        "Data sent to or received from peers per fence"
+    );
+    describe_counter!(DMODEX_ISSUED, "Direct modex requests sent to peers");
+    describe_counter!(DMODEX_SERVED, "Direct modex requests answered for peers");
+    describe_histogram!(
+        DMODEX_DURATION,
+        Unit::Seconds,
+        "Time taken to serve direct modex requests from local processes"
+    );
+    describe_counter!(CREDENTIALS_ISSUED, "Credentials issued to local processes");
+    describe_counter!(
+        CREDENTIALS_VALIDATED,
+        "Credentials validated for local processes, by result"
+    );
+    describe_counter!(CONNECT_RETRIES, "Connections to peers that were retried");
+    describe_counter!(
+        WATCH_RESTARTS,
+        "Kubernetes API watches restarted after errors"
+    );
+    Ok(())
+}
+
+/// Log to stderr. `level` is a filter in `RUST_LOG` syntax, which takes
+/// precedence over `RUST_LOG` itself.
+pub fn init_logging(format: LogFormat, level: Option<&str>) -> Result<(), ParseError> {
+    let filter = match level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy(),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+    Ok(())
+}
+
+/// Resolves when a dump of internal state is requested through `trigger`.
+/// Never resolves if there is no trigger, or it is closed.
+pub async fn dump_requested(trigger: &mut Option<broadcast::Receiver<()>>) {
+    let Some(rx) = trigger else {
+        return future::pending().await;
+    };
+    if let Err(broadcast::error::RecvError::Closed) = rx.recv().await {
+        *trigger = None;
+        future::pending().await
+    }
+}
@@ -0,0 +1,310 @@
+//! Environment variables added to each process, with values templated on its
+//! rank, e.g. `CUDA_VISIBLE_DEVICES={local_rank}`.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fs, io, mem,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid environment template (expected KEY=TEMPLATE): {0:?}")]
+    Invalid(String),
+    #[error("unknown variable {{{0}}} in environment template")]
+    UnknownVariable(String),
+    #[error("unmatched brace in environment template: {0:?}")]
+    Unmatched(String),
+    #[error("unable to read {0}")]
+    Read(PathBuf, #[source] io::Error),
+}
+
+/// Where a process is in the job.
+#[derive(Debug, Clone, Copy)]
+pub struct RankInfo<'a> {
+    pub rank: u32,
+    /// Index of the process within this pod.
+    pub local_rank: u32,
+    pub node_rank: u32,
+    pub namespace: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+    Rank,
+    LocalRank,
+    NodeRank,
+    Namespace,
+}
+
+impl FromStr for Variable {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rank" => Ok(Self::Rank),
+            "local_rank" => Ok(Self::LocalRank),
+            "node_rank" => Ok(Self::NodeRank),
+            "namespace" => Ok(Self::Namespace),
+            _ => Err(Error::UnknownVariable(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Variable(Variable),
+}
+
+/// An environment variable, whose value may refer to `{rank}`, `{local_rank}`,
+/// `{node_rank}` and `{namespace}`. Literal braces are written `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvTemplate {
+    key: String,
+    parts: Vec<Part>,
+}
+
+impl FromStr for EnvTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, template) = s
+            .split_once('=')
+            .filter(|(key, _)| !key.is_empty())
+            .ok_or_else(|| Error::Invalid(s.to_owned()))?;
+        let unmatched = || Error::Unmatched(template.to_owned());
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..i]);
+            let brace = if rest[i..].starts_with('{') { '{' } else { '}' };
+            let tail = &rest[i + 1..];
+            if let Some(tail) = tail.strip_prefix(brace) {
+                literal.push(brace);
+                rest = tail;
+                continue;
+            }
+            if brace == '}' {
+                return Err(unmatched());
+            }
+            let end = tail.find('}').ok_or_else(unmatched)?;
+            if !literal.is_empty() {
+                parts.push(Part::Literal(mem::take(&mut literal)));
+            }
+            parts.push(Part::Variable(tail[..end].parse()?));
+            rest = &tail[end + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self {
+            key: key.to_owned(),
+            parts,
+        })
+    }
+}
+
+impl EnvTemplate {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn render(&self, info: &RankInfo) -> String {
+        let mut value = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => value.push_str(s),
+                Part::Variable(Variable::Rank) => value.push_str(&info.rank.to_string()),
+                Part::Variable(Variable::LocalRank) => value.push_str(&info.local_rank.to_string()),
+                Part::Variable(Variable::NodeRank) => value.push_str(&info.node_rank.to_string()),
+                Part::Variable(Variable::Namespace) => value.push_str(info.namespace),
+            }
+        }
+        value
+    }
+}
+
+/// Variables to set for every process. Overrides replace those set by PMIx,
+/// while defaults only apply if neither PMIx nor the process' own environment
+/// sets them. Later templates for the same variable take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvTemplates {
+    pub overrides: Vec<EnvTemplate>,
+    pub defaults: Vec<EnvTemplate>,
+}
+
+impl EnvTemplates {
+    /// Parse templates from a file with one per line: `KEY=TEMPLATE` to
+    /// override, or `KEY?=TEMPLATE` for a default. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path).map_err(|err| Error::Read(path.to_owned(), err))?;
+        let mut templates = Self::default();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let default = line
+                .split_once('=')
+                .and_then(|(key, template)| Some((key.strip_suffix('?')?, template)));
+            match default {
+                Some((key, template)) => {
+                    let template = format!("{}={}", key, template).parse()?;
+                    templates.defaults.push(template);
+                }
+                None => templates.overrides.push(line.parse()?),
+            }
+        }
+        Ok(templates)
+    }
+
+    /// Add templates from `other`, which take precedence over ours.
+    pub fn extend(&mut self, other: Self) {
+        self.overrides.extend(other.overrides);
+        self.defaults.extend(other.defaults);
+    }
+
+    /// Apply the templates for the process at `info` to the environment PMIx
+    /// produced for it. `preset` tells whether the process has a variable
+    /// regardless of PMIx (e.g. inherited, or given with `-x`), which defaults
+    /// then leave alone.
+    pub fn apply(
+        &self,
+        vars: &mut Vec<(OsString, OsString)>,
+        info: &RankInfo,
+        preset: impl Fn(&OsStr) -> bool,
+    ) {
+        let is_set = |vars: &[(OsString, OsString)], key: &str| {
+            vars.iter().position(|(k, _)| k == OsStr::new(key))
+        };
+        let pmix_keys = vars.len();
+        for template in &self.defaults {
+            if preset(OsStr::new(template.key())) {
+                continue;
+            }
+            // Only PMIx's variables prevent a default, so later defaults win
+            match is_set(vars, template.key()) {
+                Some(i) if i < pmix_keys => {}
+                Some(i) => vars[i].1 = template.render(info).into(),
+                None => vars.push((template.key().into(), template.render(info).into())),
+            }
+        }
+        for template in &self.overrides {
+            match is_set(vars, template.key()) {
+                Some(i) => vars[i].1 = template.render(info).into(),
+                None => vars.push((template.key().into(), template.render(info).into())),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use super::*;
+
+    const INFO: RankInfo = RankInfo {
+        rank: 5,
+        local_rank: 1,
+        node_rank: 2,
+        namespace: "job",
+    };
+
+    #[test]
+    fn test_render() {
+        let template = "FOO={namespace}-{rank}/{local_rank}.{node_rank}"
+            .parse::<EnvTemplate>()
+            .unwrap();
+        assert_eq!(template.key(), "FOO");
+        assert_eq!(template.render(&INFO), "job-5/1.2");
+
+        let template = "FOO={{rank}}={rank}".parse::<EnvTemplate>().unwrap();
+        assert_eq!(template.render(&INFO), "{rank}=5");
+        let template = "FOO=".parse::<EnvTemplate>().unwrap();
+        assert_eq!(template.render(&INFO), "");
+
+        assert!(matches!(
+            "FOO={bar}".parse::<EnvTemplate>(),
+            Err(Error::UnknownVariable(_))
+        ));
+        assert!(matches!(
+            "FOO={rank".parse::<EnvTemplate>(),
+            Err(Error::Unmatched(_))
+        ));
+        assert!(matches!(
+            "FOO=rank}".parse::<EnvTemplate>(),
+            Err(Error::Unmatched(_))
+        ));
+        assert!(matches!(
+            "=foo".parse::<EnvTemplate>(),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            "FOO".parse::<EnvTemplate>(),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply() {
+        let templates = EnvTemplates {
+            overrides: vec!["PMIX_FOO=bar".parse().unwrap()],
+            defaults: vec![
+                "PMIX_BAR=baz".parse().unwrap(),
+                "DEVICES={local_rank}".parse().unwrap(),
+                "DEVICES=0,{local_rank}".parse().unwrap(),
+            ],
+        };
+        let mut vars = vec![
+            ("PMIX_FOO".into(), "foo".into()),
+            ("PMIX_BAR".into(), "bar".into()),
+        ];
+        templates.apply(&mut vars, &INFO, |_| false);
+        let expected: [(OsString, OsString); 3] = [
+            ("PMIX_FOO".into(), "bar".into()),
+            ("PMIX_BAR".into(), "bar".into()),
+            ("DEVICES".into(), "0,1".into()),
+        ];
+        assert_eq!(vars, expected);
+
+        // Variables the process has anyway keep their value over defaults,
+        // but not over overrides
+        let templates = EnvTemplates {
+            overrides: vec!["CUDA_VISIBLE_DEVICES={local_rank}".parse().unwrap()],
+            defaults: vec!["DEVICES={local_rank}".parse().unwrap()],
+        };
+        let mut vars = Vec::new();
+        templates.apply(&mut vars, &INFO, |key| {
+            key == "DEVICES" || key == "CUDA_VISIBLE_DEVICES"
+        });
+        let expected: [(OsString, OsString); 1] = [("CUDA_VISIBLE_DEVICES".into(), "1".into())];
+        assert_eq!(vars, expected);
+    }
+
+    #[test]
+    fn test_load() {
+        let tmpdir = tempdir::TempDir::new("template-test").unwrap();
+        let path = tmpdir.path().join("env");
+        fs::write(
+            &path,
+            "# Devices\nCUDA_VISIBLE_DEVICES={local_rank}\n\nOMPI_MCA_foo?=bar\n",
+        )
+        .unwrap();
+        let templates = EnvTemplates::load(&path).unwrap();
+        assert_eq!(templates.overrides.len(), 1);
+        assert_eq!(templates.overrides[0].key(), "CUDA_VISIBLE_DEVICES");
+        assert_eq!(templates.defaults.len(), 1);
+        assert_eq!(templates.defaults[0].key(), "OMPI_MCA_foo");
+
+        fs::write(&path, "FOO={bar}\n").unwrap();
+        assert!(EnvTemplates::load(&path).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! TLS for connections between peers.
+//!
+//! Peers authenticate each other with certificates signed by a CA shared by
+//! the job, either mounted from a Secret (e.g. one issued by cert-manager) or
+//! generated at startup and distributed through the Kubernetes API. Peers are
+//! addressed by pod IP, so certificates are verified against the fixed name
+//! [`SERVER_NAME`] rather than the address.
+
+use std::{fs, io, path::Path, sync::Arc};
+
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::{
+    ClientConfig, RootCertStore, ServerConfig,
+    pki_types::{
+        CertificateDer, PrivateKeyDer, ServerName,
+        pem::{self, PemObject},
+    },
+    server::{VerifierBuilderError, WebPkiClientVerifier},
+};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// The DNS name every peer certificate must be valid for.
+pub const SERVER_NAME: &str = "pmi-k8s";
+
+/// Files read by [`Tls::load`], as in a `kubernetes.io/tls` Secret.
+pub const CERT_FILE: &str = "tls.crt";
+pub const KEY_FILE: &str = "tls.key";
+pub const CA_FILE: &str = "ca.crt";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unable to read {0}")]
+    Read(&'static str, #[source] io::Error),
+    #[error("invalid PEM data in {0}")]
+    Pem(&'static str, #[source] pem::Error),
+    #[error("invalid CA certificate")]
+    Verifier(#[from] VerifierBuilderError),
+    #[error("invalid TLS configuration")]
+    Config(#[from] rustls::Error),
+    #[error("unable to generate certificate")]
+    Generate(#[from] rcgen::Error),
+}
+
+/// This pod's certificate, and the CA its peers' certificates must chain to.
+/// Both sides of every connection present a certificate.
+#[derive(Clone)]
+pub struct Tls {
+    connector: TlsConnector,
+    acceptor: TlsAcceptor,
+}
+
+impl Tls {
+    pub fn new(
+        ca: Vec<CertificateDer<'static>>,
+        chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self, Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in ca {
+            roots.add(cert)?;
+        }
+        let roots = Arc::new(roots);
+
+        let verifier = WebPkiClientVerifier::builder(roots.clone()).build()?;
+        let server = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(chain.clone(), key.clone_key())?;
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(chain, key)?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(client)),
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+        })
+    }
+
+    /// Load this pod's certificate and key, and the CA, from `dir`. This is
+    /// usually a mounted Secret with [`CERT_FILE`], [`KEY_FILE`] and
+    /// [`CA_FILE`].
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let read =
+            |name: &'static str| fs::read(dir.join(name)).map_err(|err| Error::Read(name, err));
+        let ca = read_certs(CA_FILE, &read(CA_FILE)?)?;
+        let chain = read_certs(CERT_FILE, &read(CERT_FILE)?)?;
+        let key = PrivateKeyDer::from_pem_slice(&read(KEY_FILE)?)
+            .map_err(|err| Error::Pem(KEY_FILE, err))?;
+        Self::new(ca, chain, key)
+    }
+
+    pub(crate) async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        s: S,
+    ) -> Result<TlsStream<S>, io::Error> {
+        #[allow(clippy::unwrap_used, reason = "SERVER_NAME is a valid DNS name")]
+        let name = ServerName::try_from(SERVER_NAME).unwrap();
+        Ok(self.connector.connect(name, s).await?.into())
+    }
+
+    pub(crate) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        s: S,
+    ) -> Result<TlsStream<S>, io::Error> {
+        Ok(self.acceptor.accept(s).await?.into())
+    }
+}
+
+fn read_certs(name: &'static str, pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, Error> {
+    CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Error::Pem(name, err))
+}
+
+/// A CA for a single job, which every pod uses to issue its own certificate.
+pub struct Ca {
+    cert: String,
+    key: String,
+}
+
+impl Ca {
+    pub fn generate() -> Result<Self, Error> {
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "pmi-k8s job CA");
+        let key = KeyPair::generate()?;
+        let cert = params.self_signed(&key)?;
+        Ok(Self {
+            cert: cert.pem(),
+            key: key.serialize_pem(),
+        })
+    }
+
+    /// A CA from its PEM-encoded certificate and private key.
+    pub fn from_pem(cert: String, key: String) -> Self {
+        Self { cert, key }
+    }
+
+    pub fn cert_pem(&self) -> &str {
+        &self.cert
+    }
+
+    pub fn key_pem(&self) -> &str {
+        &self.key
+    }
+
+    /// Issue a new certificate for this pod, and use it for TLS.
+    pub fn issue(&self) -> Result<Tls, Error> {
+        let ca_key = KeyPair::from_pem(&self.key)?;
+        let ca_cert = CertificateParams::from_ca_cert_pem(&self.cert)?.self_signed(&ca_key)?;
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![SERVER_NAME.to_owned()])?;
+        params.extended_key_usages = vec![
+            ExtendedKeyUsagePurpose::ServerAuth,
+            ExtendedKeyUsagePurpose::ClientAuth,
+        ];
+        let cert = params.signed_by(&key, &ca_cert, &ca_key)?;
+
+        let ca = read_certs(CA_FILE, self.cert.as_bytes())?;
+        let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+        Tls::new(ca, vec![cert.der().clone()], key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use futures::future::join;
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_issue() {
+        let ca = Ca::generate().unwrap();
+        let ca = Ca::from_pem(ca.cert_pem().to_owned(), ca.key_pem().to_owned());
+        let (a, b) = (ca.issue().unwrap(), ca.issue().unwrap());
+
+        let (c, s) = io::duplex(4096);
+        let (c, s) = join(a.connect(c), b.accept(s)).await;
+        let (mut c, mut s) = (c.unwrap(), s.unwrap());
+        c.write_all(b"foo").await.unwrap();
+        c.flush().await.unwrap();
+        let mut buf = [0; 3];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"foo");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_ca() {
+        let a = Ca::generate().unwrap().issue().unwrap();
+        let b = Ca::generate().unwrap().issue().unwrap();
+
+        let (c, s) = io::duplex(4096);
+        let (c, s) = join(a.connect(c), b.accept(s)).await;
+        assert!(c.is_err());
+        assert!(s.is_err());
+    }
+}
@@ -0,0 +1,744 @@
+//! Framing for connections between peers.
+//!
+//! Every connection starts with a handshake, in which the connecting side
+//! sends a [`Hello`] and the accepting side replies with its own. If the job
+//! has a shared [`Secret`], both sides then prove knowledge of it by sending
+//! an HMAC of both hellos' nonces. If both sides use TLS, the connection is
+//! then upgraded to TLS. Messages after that are sent as frames, each prefixed
+//! by its length as a big-endian `u32`. Large payloads may be compressed, if
+//! both sides support it.
+
+use std::{
+    fmt,
+    io::Read as _,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net, time,
+};
+
+use crate::{
+    net::{Backoff, connect_peer},
+    peer::Endpoint,
+    tls::Tls,
+};
+
+const MAGIC: [u8; 4] = *b"PMIK";
+/// Protocol version, which must match exactly between peers.
+pub const VERSION: u16 = 4;
+const NONCE_LEN: usize = 16;
+const HELLO_LEN: usize = 11 + NONCE_LEN;
+const PROOF_LEN: usize = 32;
+/// The largest frame that will be sent or received, which stops a peer making
+/// us allocate arbitrary amounts of memory.
+pub const MAX_FRAME: usize = 1 << 30;
+/// How long an accepted connection has to complete the handshake, so idle
+/// connections can't tie up an endpoint.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The sender will authenticate with the job's shared secret.
+pub const FEATURE_AUTH: u32 = 1 << 0;
+/// The sender will upgrade the connection to TLS after the handshake.
+pub const FEATURE_TLS: u32 = 1 << 1;
+/// The sender can receive zstd-compressed payloads.
+pub const FEATURE_ZSTD: u32 = 1 << 2;
+/// Features that only need to be supported (not configured) by both sides.
+const SUPPORTED: u32 = FEATURE_ZSTD;
+
+const CODEC_NONE: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+// Payloads are sent once, so favour speed over size
+const ZSTD_LEVEL: i32 = 1;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("I/O error communicating with peer")]
+    Io(#[from] io::Error),
+    #[error("connection closed during handshake")]
+    Handshake(#[source] io::Error),
+    #[error("connection is not from a pmi-k8s peer")]
+    BadMagic,
+    #[error("peer uses protocol version {theirs}, but this is version {ours}")]
+    VersionMismatch { ours: u16, theirs: u16 },
+    #[error("expected a {expected:?} peer, but connected to endpoint {actual}")]
+    WrongEndpoint { expected: Endpoint, actual: u8 },
+    #[error("peer failed to authenticate")]
+    Unauthenticated,
+    #[error("peer requires authentication, but no secret is configured")]
+    NoSecret,
+    #[error("peer does not use TLS")]
+    Unencrypted,
+    #[error("peer requires TLS, but TLS is not configured")]
+    NoTls,
+    #[error("TLS handshake failed")]
+    Tls(#[source] io::Error),
+    #[error("invalid compressed payload")]
+    Compression(#[source] io::Error),
+    #[error("unable to generate nonce")]
+    Random(#[from] getrandom::Error),
+}
+
+impl Error {
+    /// Whether the connection was rejected before it was established, i.e. it
+    /// was not from another pmi-k8s peer in this job.
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self,
+            Self::Handshake(_)
+                | Self::BadMagic
+                | Self::Unauthenticated
+                | Self::Unencrypted
+                | Self::Tls(_)
+        )
+    }
+}
+
+/// A secret shared by all pods of a job, used to authenticate connections.
+#[derive(Clone)]
+pub struct Secret(Arc<[u8]>);
+
+impl Secret {
+    pub fn new(secret: impl Into<Arc<[u8]>>) -> Self {
+        Self(secret.into())
+    }
+
+    /// A key for `purpose`, derived from the secret.
+    pub fn derive(&self, purpose: &[u8]) -> [u8; 32] {
+        #[allow(clippy::unwrap_used, reason = "HMAC accepts keys of any length")]
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(purpose);
+        mac.finalize().into_bytes().into()
+    }
+
+    fn proof(&self, label: &[u8], sender: &Hello, receiver: &Hello) -> Hmac<Sha256> {
+        #[allow(clippy::unwrap_used, reason = "HMAC accepts keys of any length")]
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(label);
+        mac.update(&sender.nonce);
+        mac.update(&receiver.nonce);
+        mac
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// How to connect to peers. All pods of a job must agree on `secret` and
+/// `tls`.
+#[derive(Clone, Default)]
+pub struct Options {
+    pub secret: Option<Secret>,
+    pub tls: Option<Tls>,
+    /// Compress payloads of at least this many bytes.
+    pub compress_above: Option<usize>,
+    /// How to retry connecting to peers that aren't listening yet.
+    pub backoff: Backoff,
+}
+
+#[derive(Debug)]
+enum Transport<S> {
+    Plain(S),
+    Tls(Box<tokio_rustls::TlsStream<S>>),
+}
+
+/// A connection to a peer, after the handshake.
+#[derive(Debug)]
+pub struct Stream<S> {
+    transport: Transport<S>,
+    /// Optional features supported by both sides.
+    features: u32,
+}
+
+impl<S> Stream<S> {
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.transport, Transport::Tls(_))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
+    /// Send `payload` as a single frame, compressed if the peer supports it.
+    pub async fn write_payload(&mut self, payload: &Payload<'_>) -> Result<(), Error> {
+        if self.features & FEATURE_ZSTD == 0 {
+            return write_frame(self, payload.data).await;
+        }
+        match &payload.compressed {
+            Some(compressed) => {
+                #[allow(clippy::unwrap_used, reason = "Checked by Payload::new")]
+                let len = u32::try_from(payload.data.len()).unwrap().to_be_bytes();
+                write_frame_parts(self, &[&[CODEC_ZSTD], &len, compressed]).await
+            }
+            None => write_frame_parts(self, &[&[CODEC_NONE], payload.data]).await,
+        }
+    }
+
+    /// Receive a payload sent with [`Stream::write_payload`].
+    pub async fn read_payload(&mut self) -> Result<Vec<u8>, Error> {
+        let mut frame = read_frame(self).await?;
+        if self.features & FEATURE_ZSTD == 0 {
+            return Ok(frame);
+        }
+        let invalid =
+            |msg: &str| Error::Compression(io::Error::new(io::ErrorKind::InvalidData, msg));
+        match frame.first() {
+            Some(&CODEC_NONE) => {
+                frame.remove(0);
+                Ok(frame)
+            }
+            Some(&CODEC_ZSTD) => {
+                let (len, compressed) = frame[1..]
+                    .split_first_chunk::<4>()
+                    .ok_or_else(|| invalid("payload too short"))?;
+                let len = u32::from_be_bytes(*len) as usize;
+                if len > MAX_FRAME {
+                    return Err(invalid("payload too large"));
+                }
+                // Decompress into a growing buffer rather than trusting the
+                // sender's length, which could be up to a gigabyte
+                let mut data = Vec::new();
+                zstd::stream::read::Decoder::with_buffer(compressed)
+                    .map_err(Error::Compression)?
+                    .take(len as u64 + 1)
+                    .read_to_end(&mut data)
+                    .map_err(Error::Compression)?;
+                if data.len() != len {
+                    return Err(invalid("payload has the wrong length"));
+                }
+                Ok(data)
+            }
+            _ => Err(invalid("unknown codec")),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Transport::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().transport {
+            Transport::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Transport::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Plain(s) => Pin::new(s).poll_flush(cx),
+            Transport::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().transport {
+            Transport::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Transport::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Data to send to one or more peers, compressed up front if it is large
+/// enough and compresses well.
+pub struct Payload<'a> {
+    data: &'a [u8],
+    compressed: Option<Vec<u8>>,
+}
+
+impl<'a> Payload<'a> {
+    pub fn new(data: &'a [u8], options: &Options) -> Result<Self, Error> {
+        let compressed = match options.compress_above {
+            Some(threshold) if data.len() >= threshold && u32::try_from(data.len()).is_ok() => {
+                let compressed =
+                    zstd::bulk::compress(data, ZSTD_LEVEL).map_err(Error::Compression)?;
+                Some(compressed).filter(|compressed| compressed.len() < data.len())
+            }
+            _ => None,
+        };
+        Ok(Self { data, compressed })
+    }
+
+    /// The size of the payload after compression, if it was compressed.
+    pub fn compressed_len(&self) -> Option<usize> {
+        self.compressed.as_ref().map(Vec::len)
+    }
+}
+
+/// The first message sent on each connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub endpoint: u8,
+    /// Optional protocol features supported by the sender, as a bit set.
+    pub features: u32,
+    nonce: [u8; NONCE_LEN],
+}
+
+fn endpoint_id(endpoint: Endpoint) -> u8 {
+    match endpoint {
+        Endpoint::Fence => 0,
+        Endpoint::Modex => 1,
+        Endpoint::Credential => 2,
+        Endpoint::Handshake => 3,
+        Endpoint::Query => 4,
+    }
+}
+
+impl Hello {
+    fn new(endpoint: Endpoint, options: &Options) -> Result<Self, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        let mut features = 0;
+        if options.secret.is_some() {
+            getrandom::fill(&mut nonce)?;
+            features |= FEATURE_AUTH;
+        }
+        if options.tls.is_some() {
+            features |= FEATURE_TLS;
+        }
+        features |= SUPPORTED;
+        Ok(Self {
+            version: VERSION,
+            endpoint: endpoint_id(endpoint),
+            features,
+            nonce,
+        })
+    }
+
+    fn serialize(&self) -> [u8; HELLO_LEN] {
+        let mut buf = [0; HELLO_LEN];
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6] = self.endpoint;
+        buf[7..11].copy_from_slice(&self.features.to_be_bytes());
+        buf[11..].copy_from_slice(&self.nonce);
+        buf
+    }
+
+    fn parse(buf: [u8; HELLO_LEN]) -> Result<Self, Error> {
+        if buf[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        #[allow(clippy::unwrap_used, reason = "Sizes are statically known")]
+        Ok(Self {
+            version: u16::from_be_bytes(buf[4..6].try_into().unwrap()),
+            endpoint: buf[6],
+            features: u32::from_be_bytes(buf[7..11].try_into().unwrap()),
+            nonce: buf[11..].try_into().unwrap(),
+        })
+    }
+
+    fn check(&self, endpoint: Endpoint) -> Result<(), Error> {
+        if self.version != VERSION {
+            Err(Error::VersionMismatch {
+                ours: VERSION,
+                theirs: self.version,
+            })
+        } else if self.endpoint != endpoint_id(endpoint) {
+            Err(Error::WrongEndpoint {
+                expected: endpoint,
+                actual: self.endpoint,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    s: &mut S,
+    endpoint: Endpoint,
+    options: &Options,
+    initiator: bool,
+) -> Result<Hello, Error> {
+    let ours = Hello::new(endpoint, options)?;
+    if initiator {
+        s.write_all(&ours.serialize())
+            .await
+            .map_err(Error::Handshake)?;
+    }
+    let mut buf = [0; HELLO_LEN];
+    s.read_exact(&mut buf).await.map_err(Error::Handshake)?;
+    let theirs = Hello::parse(buf)?;
+    if !initiator {
+        // Reply even if the versions differ, so both sides can report it
+        s.write_all(&ours.serialize())
+            .await
+            .map_err(Error::Handshake)?;
+    }
+    theirs.check(endpoint)?;
+
+    match (&options.tls, theirs.features & FEATURE_TLS != 0) {
+        (None, true) => return Err(Error::NoTls),
+        (Some(_), false) => return Err(Error::Unencrypted),
+        _ => {}
+    }
+    match (&options.secret, theirs.features & FEATURE_AUTH != 0) {
+        (None, false) => {}
+        (None, true) => return Err(Error::NoSecret),
+        (Some(_), false) => return Err(Error::Unauthenticated),
+        (Some(secret), true) => {
+            // Labels stop a proof being reflected back to its sender
+            let (label, their_label) = if initiator {
+                (b"connect", b"accept\0")
+            } else {
+                (b"accept\0", b"connect")
+            };
+            let proof = secret.proof(label, &ours, &theirs).finalize().into_bytes();
+            s.write_all(&proof).await.map_err(Error::Handshake)?;
+            let mut buf = [0; PROOF_LEN];
+            s.read_exact(&mut buf).await.map_err(Error::Handshake)?;
+            secret
+                .proof(their_label, &theirs, &ours)
+                .verify_slice(&buf)
+                .map_err(|_| Error::Unauthenticated)?;
+        }
+    }
+    Ok(theirs)
+}
+
+/// Upgrade a connection to TLS, if configured, after the handshake has checked
+/// that both sides agree. Also records the features both sides support.
+async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(
+    s: S,
+    theirs: &Hello,
+    options: &Options,
+    initiator: bool,
+) -> Result<Stream<S>, Error> {
+    let transport = match &options.tls {
+        None => Transport::Plain(s),
+        Some(tls) if initiator => {
+            Transport::Tls(Box::new(tls.connect(s).await.map_err(Error::Tls)?))
+        }
+        Some(tls) => Transport::Tls(Box::new(tls.accept(s).await.map_err(Error::Tls)?)),
+    };
+    Ok(Stream {
+        transport,
+        features: theirs.features & SUPPORTED,
+    })
+}
+
+/// Connect to the `endpoint` of a peer, and perform the handshake.
+pub async fn connect(
+    peer: &SocketAddr,
+    endpoint: Endpoint,
+    options: &Options,
+) -> Result<Stream<net::TcpStream>, Error> {
+    let mut s = connect_peer(peer, &options.backoff).await?;
+    let theirs = handshake(&mut s, endpoint, options, true).await?;
+    upgrade(s, &theirs, options, true).await
+}
+
+/// Perform the handshake on a connection accepted by `endpoint`. Fails if the
+/// handshake doesn't complete within [`ACCEPT_TIMEOUT`].
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    s: S,
+    endpoint: Endpoint,
+    options: &Options,
+) -> Result<Stream<S>, Error> {
+    accept_within(s, endpoint, options, ACCEPT_TIMEOUT).await
+}
+
+async fn accept_within<S: AsyncRead + AsyncWrite + Unpin>(
+    mut s: S,
+    endpoint: Endpoint,
+    options: &Options,
+    timeout: Duration,
+) -> Result<Stream<S>, Error> {
+    let accept = async move {
+        let theirs = handshake(&mut s, endpoint, options, false).await?;
+        upgrade(s, &theirs, options, false).await
+    };
+    time::timeout(timeout, accept).await.map_err(|_| {
+        Error::Handshake(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "handshake timed out",
+        ))
+    })?
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(w: &mut W, data: &[u8]) -> Result<(), Error> {
+    write_frame_parts(w, &[data]).await
+}
+
+/// Write the concatenation of `parts` as a single frame.
+async fn write_frame_parts<W: AsyncWrite + Unpin>(w: &mut W, parts: &[&[u8]]) -> Result<(), Error> {
+    let len = parts.iter().map(|part| part.len()).sum::<usize>();
+    let len = u32::try_from(len)
+        .ok()
+        .filter(|_| len <= MAX_FRAME)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    w.write_all(&len.to_be_bytes()).await?;
+    for part in parts {
+        w.write_all(part).await?;
+    }
+    // TLS buffers writes, which would otherwise be lost if the connection is
+    // dropped without shutting it down
+    w.flush().await?;
+    Ok(())
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let mut len = [0; 4];
+    r.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large").into());
+    }
+    // Only allocate as much as the peer actually sends
+    let mut data = Vec::new();
+    r.take(len as u64).read_to_end(&mut data).await?;
+    if data.len() != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+    use futures::future::join;
+
+    use super::*;
+    use crate::tls::Ca;
+
+    fn secured(secret: Option<&Secret>, tls: Option<&Tls>) -> Options {
+        Options {
+            secret: secret.cloned(),
+            tls: tls.cloned(),
+            ..Default::default()
+        }
+    }
+
+    async fn pair(
+        endpoints: (Endpoint, Endpoint),
+        options: (Options, Options),
+    ) -> (Result<Hello, Error>, Result<Hello, Error>) {
+        let (mut a, mut b) = io::duplex(256);
+        join(
+            handshake(&mut a, endpoints.0, &options.0, true),
+            handshake(&mut b, endpoints.1, &options.1, false),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let endpoints = (Endpoint::Fence, Endpoint::Fence);
+        let (a, b) = pair(endpoints, Default::default()).await;
+        let hello = Hello::new(Endpoint::Fence, &Options::default()).unwrap();
+        assert_eq!(a.unwrap(), hello);
+        assert_eq!(b.unwrap(), hello);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_mismatch() {
+        let (mut a, mut b) = io::duplex(64);
+        let theirs = Hello {
+            version: VERSION + 1,
+            ..Hello::new(Endpoint::Modex, &Options::default()).unwrap()
+        };
+        a.write_all(&theirs.serialize()).await.unwrap();
+        let err = accept(&mut b, Endpoint::Modex, &Options::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::VersionMismatch { theirs, .. } if theirs == VERSION + 1));
+        assert!(!err.is_rejected());
+
+        let (a, b) = pair((Endpoint::Fence, Endpoint::Modex), Default::default()).await;
+        assert!(matches!(a, Err(Error::WrongEndpoint { .. })));
+        assert!(matches!(b, Err(Error::WrongEndpoint { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejected() {
+        let (mut a, mut b) = io::duplex(64);
+        a.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let err = accept(&mut b, Endpoint::Fence, &Options::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BadMagic));
+        assert!(err.is_rejected());
+
+        let (a, mut b) = io::duplex(64);
+        drop(a);
+        let err = accept(&mut b, Endpoint::Fence, &Options::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Handshake(_)));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_auth() {
+        let endpoints = (Endpoint::Modex, Endpoint::Modex);
+        let secret = Secret::new(b"foo".as_slice());
+        let (a, b) = pair(
+            endpoints,
+            (secured(Some(&secret), None), secured(Some(&secret), None)),
+        )
+        .await;
+        assert_eq!(a.unwrap().features, FEATURE_AUTH | SUPPORTED);
+        assert_eq!(b.unwrap().features, FEATURE_AUTH | SUPPORTED);
+
+        let other = Secret::new(b"bar".as_slice());
+        let (a, b) = pair(
+            endpoints,
+            (secured(Some(&other), None), secured(Some(&secret), None)),
+        )
+        .await;
+        assert!(matches!(a, Err(Error::Unauthenticated)));
+        assert!(b.unwrap_err().is_rejected());
+
+        let (a, b) = pair(
+            endpoints,
+            (Options::default(), secured(Some(&secret), None)),
+        )
+        .await;
+        assert!(matches!(a, Err(Error::NoSecret)));
+        assert!(b.unwrap_err().is_rejected());
+
+        let (a, b) = pair(
+            endpoints,
+            (secured(Some(&secret), None), Options::default()),
+        )
+        .await;
+        assert!(matches!(a, Err(Error::Unauthenticated)));
+        assert!(matches!(b, Err(Error::NoSecret)));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_tls() {
+        let ca = Ca::generate().unwrap();
+        let options = secured(None, Some(&ca.issue().unwrap()));
+        let theirs = secured(None, Some(&ca.issue().unwrap()));
+        let (mut a, b) = io::duplex(4096);
+        let (a, b) = join(
+            async {
+                let theirs = handshake(&mut a, Endpoint::Modex, &options, true).await?;
+                upgrade(a, &theirs, &options, true).await
+            },
+            accept(b, Endpoint::Modex, &theirs),
+        )
+        .await;
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert!(a.is_encrypted());
+        write_frame(&mut a, b"foo").await.unwrap();
+        assert_eq!(read_frame(&mut b).await.unwrap(), b"foo");
+
+        let endpoints = (Endpoint::Modex, Endpoint::Modex);
+        let (a, b) = pair(endpoints, (options, Options::default())).await;
+        assert!(matches!(a, Err(Error::Unencrypted)));
+        assert!(matches!(b, Err(Error::NoTls)));
+        assert!(a.unwrap_err().is_rejected());
+    }
+
+    #[tokio::test]
+    async fn test_payload() {
+        let data = vec![7; 4096];
+        let options = Options {
+            compress_above: Some(1024),
+            ..Default::default()
+        };
+        let payload = Payload::new(&data, &options).unwrap();
+        assert!(payload.compressed.is_some());
+        assert!(
+            Payload::new(&data[..16], &options)
+                .unwrap()
+                .compressed
+                .is_none()
+        );
+
+        for features in [0, FEATURE_ZSTD] {
+            let (a, b) = io::duplex(8192);
+            let mut a = Stream {
+                transport: Transport::Plain(a),
+                features,
+            };
+            let mut b = Stream {
+                transport: Transport::Plain(b),
+                features,
+            };
+            a.write_payload(&payload).await.unwrap();
+            assert_eq!(b.read_payload().await.unwrap(), data);
+            a.write_payload(&Payload::new(b"foo", &options).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(b.read_payload().await.unwrap(), b"foo");
+        }
+
+        let (mut a, b) = io::duplex(256);
+        let mut b = Stream {
+            transport: Transport::Plain(b),
+            features: FEATURE_ZSTD,
+        };
+        let len = u32::MAX.to_be_bytes();
+        write_frame_parts(&mut a, &[&[CODEC_ZSTD], &len, b"foo"])
+            .await
+            .unwrap();
+        assert!(matches!(b.read_payload().await, Err(Error::Compression(_))));
+
+        // Lengths that don't match the decompressed data are rejected, without
+        // allocating the claimed length up front
+        let compressed = zstd::bulk::compress(b"foo", 0).unwrap();
+        for len in [MAX_FRAME as u32, 2] {
+            write_frame_parts(&mut a, &[&[CODEC_ZSTD], &len.to_be_bytes(), &compressed])
+                .await
+                .unwrap();
+            assert!(matches!(b.read_payload().await, Err(Error::Compression(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"foo").await.unwrap();
+        write_frame(&mut buf, b"").await.unwrap();
+        assert_eq!(buf, b"\0\0\0\x03foo\0\0\0\0");
+
+        let mut r = buf.as_slice();
+        assert_eq!(read_frame(&mut r).await.unwrap(), b"foo");
+        assert_eq!(read_frame(&mut r).await.unwrap(), b"");
+        assert!(read_frame(&mut r).await.is_err());
+
+        let mut r: &[u8] = b"\xff\xff\xff\xfffoo";
+        let err = read_frame(&mut r).await.unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::InvalidData));
+        let mut r: &[u8] = b"\0\0\0\x04foo";
+        assert!(read_frame(&mut r).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_accept_timeout() {
+        let (_a, b) = io::duplex(256);
+        let timeout = Duration::from_millis(10);
+        let result = accept_within(b, Endpoint::Fence, &Options::default(), timeout).await;
+        let err = result.unwrap_err();
+        assert!(matches!(&err, Error::Handshake(err) if err.kind() == io::ErrorKind::TimedOut));
+        assert!(err.is_rejected());
+    }
+}